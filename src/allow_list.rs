@@ -0,0 +1,237 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use poise::serenity_prelude::{GuildId, UserId};
+use rusqlite::{params, OptionalExtension, Row};
+
+use crate::database::Database;
+
+/// How long an allow list grant lasts.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AllowListDuration {
+    Permanent,
+    For(TimeDelta),
+}
+
+impl AllowListDuration {
+    /// Parses durations like `30m`, `12h`, `1d12h`, `2w` or `permanent`.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim().to_lowercase();
+        if matches!(input.as_str(), "permanent" | "forever" | "perm") {
+            return Ok(AllowListDuration::Permanent);
+        }
+        let mut total = TimeDelta::zero();
+        let mut number = String::new();
+        for c in input.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let amount: i64 = number
+                .parse()
+                .map_err(|_| format!("Expected a number before `{c}` in `{input}`"))?;
+            number.clear();
+            let unit = match c {
+                'm' => TimeDelta::try_minutes(amount),
+                'h' => TimeDelta::try_hours(amount),
+                'd' => TimeDelta::try_days(amount),
+                'w' => TimeDelta::try_weeks(amount),
+                _ => return Err(format!("Unknown unit `{c}`, use m, h, d or w")),
+            };
+            total = unit
+                .and_then(|unit| total.checked_add(&unit))
+                .ok_or_else(|| format!("`{input}` is too long, use permanent instead"))?;
+        }
+        if !number.is_empty() {
+            return Err(format!("Missing a unit after `{number}`, use m, h, d or w"));
+        }
+        if total <= TimeDelta::zero() {
+            return Err("Duration must be longer than zero".to_string());
+        }
+        let duration = AllowListDuration::For(total);
+        duration.expires_at(Utc::now())?;
+        Ok(duration)
+    }
+
+    /// When a grant made at `from` ends, `None` if it never does. Fails for durations that end
+    /// after the last date that can be stored.
+    pub fn expires_at(&self, from: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        match self {
+            AllowListDuration::Permanent => Ok(None),
+            AllowListDuration::For(duration) => from
+                .checked_add_signed(*duration)
+                .map(Some)
+                .ok_or_else(|| "That duration is too long, use permanent instead".to_string()),
+        }
+    }
+}
+
+/// A member that skips all checks, either permanently or until `expires_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllowListEntry {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    pub granted_by: UserId,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AllowListEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            guild_id: GuildId::new(row.get("guild_id")?),
+            user_id: UserId::new(row.get("user_id")?),
+            expires_at: row.get("expires_at")?,
+            reason: row.get("reason")?,
+            granted_by: UserId::new(row.get("granted_by")?),
+            created_at: row.get("created_at")?,
+        })
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map(|end| now < end).unwrap_or(true)
+    }
+}
+
+impl Database {
+    /// Adds a member to the allow list, replacing any existing grant for them.
+    pub fn grant_allow_list(&self, entry: &AllowListEntry) -> rusqlite::Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO allow_list (guild_id, user_id, expires_at, reason, granted_by, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.guild_id.get(),
+                entry.user_id.get(),
+                entry.expires_at,
+                entry.reason,
+                entry.granted_by.get(),
+                entry.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns false if the member wasn't on the allow list.
+    pub fn revoke_allow_list(&self, guild_id: GuildId, user_id: UserId) -> rusqlite::Result<bool> {
+        let removed = self.connection().execute(
+            "DELETE FROM allow_list WHERE guild_id = ?1 AND user_id = ?2",
+            params![guild_id.get(), user_id.get()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    pub fn allow_list_entry(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> rusqlite::Result<Option<AllowListEntry>> {
+        self.connection()
+            .query_row(
+                "SELECT * FROM allow_list WHERE guild_id = ?1 AND user_id = ?2",
+                params![guild_id.get(), user_id.get()],
+                AllowListEntry::from_row,
+            )
+            .optional()
+    }
+
+    pub fn allow_list(&self, guild_id: GuildId) -> rusqlite::Result<Vec<AllowListEntry>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT * FROM allow_list WHERE guild_id = ?1 ORDER BY created_at")?;
+        let entries = statement
+            .query_map([guild_id.get()], AllowListEntry::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Removes every grant that expired before `now`.
+    pub fn prune_allow_list(&self, now: DateTime<Utc>) -> rusqlite::Result<usize> {
+        self.connection().execute(
+            "DELETE FROM allow_list WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            [now],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(
+            AllowListDuration::parse("permanent"),
+            Ok(AllowListDuration::Permanent)
+        );
+        assert_eq!(
+            AllowListDuration::parse("30m"),
+            Ok(AllowListDuration::For(TimeDelta::minutes(30)))
+        );
+        assert_eq!(
+            AllowListDuration::parse("1d12h"),
+            Ok(AllowListDuration::For(TimeDelta::hours(36)))
+        );
+        assert_eq!(
+            AllowListDuration::parse(" 2W "),
+            Ok(AllowListDuration::For(TimeDelta::weeks(2)))
+        );
+        assert!(AllowListDuration::parse("").is_err());
+        assert!(AllowListDuration::parse("0d").is_err());
+        assert!(AllowListDuration::parse("12").is_err());
+        assert!(AllowListDuration::parse("d").is_err());
+        assert!(AllowListDuration::parse("3y").is_err());
+        assert!(AllowListDuration::parse("99999999999999w").is_err());
+        // fits a TimeDelta but not a date
+        assert!(AllowListDuration::parse("100000000w").is_err());
+        assert!(AllowListDuration::For(TimeDelta::weeks(100_000_000))
+            .expires_at(Utc::now())
+            .is_err());
+    }
+
+    #[test]
+    fn grants_expire() {
+        let database = Database::open_in_memory().unwrap();
+        let guild_id = GuildId::new(1);
+        let now = Utc::now();
+        let entry = |user: u64, duration: AllowListDuration| AllowListEntry {
+            guild_id,
+            user_id: UserId::new(user),
+            expires_at: duration.expires_at(now).unwrap(),
+            reason: Some("testing".to_string()),
+            granted_by: UserId::new(99),
+            created_at: now,
+        };
+        database
+            .grant_allow_list(&entry(2, AllowListDuration::Permanent))
+            .unwrap();
+        database
+            .grant_allow_list(&entry(3, AllowListDuration::For(TimeDelta::hours(1))))
+            .unwrap();
+
+        let permanent = database
+            .allow_list_entry(guild_id, UserId::new(2))
+            .unwrap()
+            .unwrap();
+        assert!(permanent.is_active(now + TimeDelta::weeks(520)));
+        let temporary = database
+            .allow_list_entry(guild_id, UserId::new(3))
+            .unwrap()
+            .unwrap();
+        assert!(temporary.is_active(now));
+        assert!(!temporary.is_active(now + TimeDelta::hours(2)));
+
+        assert_eq!(
+            database
+                .prune_allow_list(now + TimeDelta::hours(2))
+                .unwrap(),
+            1
+        );
+        assert_eq!(database.allow_list(guild_id).unwrap().len(), 1);
+        assert!(database
+            .revoke_allow_list(guild_id, UserId::new(2))
+            .unwrap());
+        assert!(!database
+            .revoke_allow_list(guild_id, UserId::new(2))
+            .unwrap());
+        assert!(database.allow_list(guild_id).unwrap().is_empty());
+    }
+}
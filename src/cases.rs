@@ -0,0 +1,435 @@
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use poise::serenity_prelude::{
+    ChannelId, Color, CreateEmbed, CreateEmbedFooter, GuildId, MessageId, RoleId, UserId,
+};
+use rusqlite::{params, OptionalExtension, Row};
+
+use crate::database::Database;
+use crate::image_hash::{decode_hash, encode_hash};
+
+pub type CaseId = i64;
+
+/// What a moderator (or the review timeout) decided to do with a case.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CaseDecision {
+    Banned,
+    Unmuted,
+    Allowlisted,
+    TimedOut,
+}
+
+impl CaseDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaseDecision::Banned => "banned",
+            CaseDecision::Unmuted => "unmuted",
+            CaseDecision::Allowlisted => "allowlisted",
+            CaseDecision::TimedOut => "timed out",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        Some(match value {
+            "banned" => CaseDecision::Banned,
+            "unmuted" => CaseDecision::Unmuted,
+            "allowlisted" => CaseDecision::Allowlisted,
+            "timed out" => CaseDecision::TimedOut,
+            _ => return None,
+        })
+    }
+}
+
+/// The punishment an offender received while their case is reviewed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AppliedPunishment {
+    MutedRole(RoleId),
+    Timeout,
+}
+
+impl AppliedPunishment {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AppliedPunishment::MutedRole(_) => "muted_role",
+            AppliedPunishment::Timeout => "timeout",
+        }
+    }
+
+    fn from_columns(punishment: Option<&str>, muted_role: Option<RoleId>) -> Option<Self> {
+        match (punishment?, muted_role) {
+            ("muted_role", Some(role)) => Some(AppliedPunishment::MutedRole(role)),
+            ("timeout", _) => Some(AppliedPunishment::Timeout),
+            _ => None,
+        }
+    }
+
+    fn muted_role(&self) -> Option<RoleId> {
+        match self {
+            AppliedPunishment::MutedRole(role) => Some(*role),
+            AppliedPunishment::Timeout => None,
+        }
+    }
+}
+
+/// An incident that is about to be recorded.
+pub struct NewCase<'a> {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub offender_id: UserId,
+    pub content: &'a str,
+    pub reason: &'a str,
+    pub confidence: Option<f32>,
+    pub media_url: Option<&'a str>,
+    /// What matched, for verdicts that don't come with media.
+    pub evidence: Option<&'a str>,
+    /// Perceptual hashes of the media's frames, used to block it.
+    pub media_hashes: &'a [u64],
+    pub punishment: AppliedPunishment,
+}
+
+/// A recorded automated moderation action.
+#[derive(Clone, Debug)]
+pub struct Case {
+    pub id: CaseId,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub offender_id: UserId,
+    pub content: String,
+    pub reason: String,
+    pub confidence: Option<f32>,
+    pub media_url: Option<String>,
+    pub evidence: Option<String>,
+    pub media_hashes: Vec<u64>,
+    pub created_at: DateTime<Utc>,
+    pub decision: Option<CaseDecision>,
+    pub moderator_id: Option<UserId>,
+    pub decided_at: Option<DateTime<Utc>>,
+    pub mod_channel_id: Option<ChannelId>,
+    pub mod_message_id: Option<MessageId>,
+    pub evidence_message_id: Option<MessageId>,
+    pub review_expires_at: Option<DateTime<Utc>>,
+    /// Missing for cases recorded before punishments were tracked, those used the muted role.
+    pub punishment: Option<AppliedPunishment>,
+}
+
+impl Case {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            guild_id: GuildId::new(row.get("guild_id")?),
+            channel_id: ChannelId::new(row.get("channel_id")?),
+            offender_id: UserId::new(row.get("offender_id")?),
+            content: row.get("content")?,
+            reason: row.get("reason")?,
+            confidence: row.get("confidence")?,
+            media_url: row.get("media_url")?,
+            evidence: row.get("evidence")?,
+            media_hashes: row
+                .get::<_, Option<String>>("media_hashes")?
+                .as_deref()
+                .map(parse_hashes)
+                .unwrap_or_default(),
+            created_at: row.get("created_at")?,
+            decision: row
+                .get::<_, Option<String>>("decision")?
+                .as_deref()
+                .and_then(CaseDecision::from_str),
+            moderator_id: row.get::<_, Option<u64>>("moderator_id")?.map(UserId::new),
+            decided_at: row.get("decided_at")?,
+            mod_channel_id: row
+                .get::<_, Option<u64>>("mod_channel_id")?
+                .map(ChannelId::new),
+            mod_message_id: row
+                .get::<_, Option<u64>>("mod_message_id")?
+                .map(MessageId::new),
+            evidence_message_id: row
+                .get::<_, Option<u64>>("evidence_message_id")?
+                .map(MessageId::new),
+            review_expires_at: row.get("review_expires_at")?,
+            punishment: AppliedPunishment::from_columns(
+                row.get::<_, Option<String>>("punishment")?.as_deref(),
+                row.get::<_, Option<u64>>("muted_role_id")?.map(RoleId::new),
+            ),
+        })
+    }
+
+    pub fn embed(&self) -> CreateEmbed {
+        let reason = match self.confidence {
+            Some(confidence) => format!("{} - {:.0}%", self.reason, confidence * 100.0),
+            None => self.reason.clone(),
+        };
+        let decision = match (self.decision, self.moderator_id) {
+            (Some(decision), Some(moderator)) => {
+                format!("{} by <@{}>", decision.as_str(), moderator)
+            }
+            (Some(decision), None) => decision.as_str().to_string(),
+            (None, _) => "pending".to_string(),
+        };
+        let mut embed = CreateEmbed::new()
+            .title(format!("Case #{}", self.id))
+            .color(if self.decision.is_some() {
+                Color::DARK_GREEN
+            } else {
+                Color::ORANGE
+            })
+            .field("Offender", format!("<@{}>", self.offender_id), true)
+            .field("Channel", format!("<#{}>", self.channel_id), true)
+            .field("Reason", reason, false)
+            .field("Decision", decision, true)
+            .field(
+                "Content",
+                code_block(&self.content, EMBED_FIELD_LIMIT),
+                false,
+            )
+            .timestamp(self.created_at);
+        if let Some(url) = &self.media_url {
+            embed = embed.field("Media", url, false);
+        }
+        if let Some(evidence) = &self.evidence {
+            embed = embed.field("Evidence", truncate(evidence, EMBED_FIELD_LIMIT), false);
+        }
+        if let Some(decided_at) = self.decided_at {
+            embed = embed.footer(CreateEmbedFooter::new(format!(
+                "Decided {}",
+                decided_at.format("%Y-%m-%d %H:%M UTC")
+            )));
+        }
+        embed
+    }
+}
+
+/// Discord rejects embed fields longer than this many characters.
+pub const EMBED_FIELD_LIMIT: usize = 1024;
+
+/// Cuts `text` down to `max_chars`, ending with `…` if anything was left out.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Message content as a code block of at most `max_chars`, backticks in the content can't close
+/// the block early.
+pub fn code_block(content: &str, max_chars: usize) -> String {
+    // a zero width space after every backtick keeps them from forming a fence
+    let escaped = content.replace('`', "`\u{200b}");
+    let fence = "```\n\n```".chars().count();
+    format!(
+        "```\n{}\n```",
+        truncate(&escaped, max_chars.saturating_sub(fence))
+    )
+}
+
+/// Hashes are stored comma separated, see [`encode_hash`].
+fn format_hashes(hashes: &[u64]) -> Option<String> {
+    (!hashes.is_empty()).then(|| hashes.iter().copied().map(encode_hash).join(","))
+}
+
+fn parse_hashes(hashes: &str) -> Vec<u64> {
+    hashes.split(',').filter_map(decode_hash).collect()
+}
+
+impl Database {
+    pub fn create_case(&self, case: &NewCase) -> rusqlite::Result<CaseId> {
+        let connection = self.connection();
+        connection.execute(
+            "INSERT INTO cases (guild_id, channel_id, offender_id, content, reason, confidence, media_url, created_at, punishment, muted_role_id, evidence, media_hashes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                case.guild_id.get(),
+                case.channel_id.get(),
+                case.offender_id.get(),
+                case.content,
+                case.reason,
+                case.confidence,
+                case.media_url,
+                Utc::now(),
+                case.punishment.as_str(),
+                case.punishment.muted_role().map(|role| role.get()),
+                case.evidence,
+                format_hashes(case.media_hashes),
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// Remembers where the review for a case lives so it can be picked up again after a restart.
+    pub fn set_case_review(
+        &self,
+        id: CaseId,
+        mod_channel_id: ChannelId,
+        mod_message_id: MessageId,
+        evidence_message_id: Option<MessageId>,
+        expires_at: DateTime<Utc>,
+    ) -> rusqlite::Result<()> {
+        self.connection().execute(
+            "UPDATE cases SET mod_channel_id = ?2, mod_message_id = ?3, evidence_message_id = ?4, review_expires_at = ?5
+             WHERE id = ?1",
+            params![
+                id,
+                mod_channel_id.get(),
+                mod_message_id.get(),
+                evidence_message_id.map(|m| m.get()),
+                expires_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records the decision for a case. Returns false if the case was already decided, so that
+    /// a button click racing the review timeout only gets acted on once.
+    pub fn resolve_case(
+        &self,
+        id: CaseId,
+        decision: CaseDecision,
+        moderator: Option<UserId>,
+    ) -> rusqlite::Result<bool> {
+        let updated = self.connection().execute(
+            "UPDATE cases SET decision = ?2, moderator_id = ?3, decided_at = ?4
+             WHERE id = ?1 AND decision IS NULL",
+            params![
+                id,
+                decision.as_str(),
+                moderator.map(|m| m.get()),
+                Utc::now()
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Clears a decision whose action failed, so the case can be resolved again.
+    pub fn reopen_case(&self, id: CaseId) -> rusqlite::Result<()> {
+        self.connection().execute(
+            "UPDATE cases SET decision = NULL, moderator_id = NULL, decided_at = NULL WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    /// Cases that are still waiting on a moderator.
+    pub fn pending_cases(&self) -> rusqlite::Result<Vec<Case>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT * FROM cases WHERE decision IS NULL AND review_expires_at IS NOT NULL",
+        )?;
+        let cases = statement
+            .query_map([], Case::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(cases)
+    }
+
+    pub fn case(&self, id: CaseId) -> rusqlite::Result<Option<Case>> {
+        self.connection()
+            .query_row("SELECT * FROM cases WHERE id = ?1", [id], Case::from_row)
+            .optional()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_round_trip() {
+        let database = Database::open_in_memory().unwrap();
+        let id = database
+            .create_case(&NewCase {
+                guild_id: GuildId::new(1),
+                channel_id: ChannelId::new(2),
+                offender_id: UserId::new(3),
+                content: "free nitro https://discorda.org",
+                reason: "Misleading URL",
+                confidence: None,
+                media_url: None,
+                evidence: Some("discorda.org looks like discord"),
+                media_hashes: &[],
+                punishment: AppliedPunishment::Timeout,
+            })
+            .unwrap();
+        let case = database.case(id).unwrap().unwrap();
+        assert_eq!(case.offender_id, UserId::new(3));
+        assert_eq!(case.decision, None);
+        assert_eq!(case.punishment, Some(AppliedPunishment::Timeout));
+        assert!(case.media_hashes.is_empty());
+        assert!(database.pending_cases().unwrap().is_empty());
+
+        database
+            .set_case_review(
+                id,
+                ChannelId::new(5),
+                MessageId::new(6),
+                None,
+                Utc::now() + chrono::TimeDelta::days(1),
+            )
+            .unwrap();
+        let pending = database.pending_cases().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].mod_message_id, Some(MessageId::new(6)));
+
+        assert!(database
+            .resolve_case(id, CaseDecision::Banned, Some(UserId::new(4)))
+            .unwrap());
+        let case = database.case(id).unwrap().unwrap();
+        assert_eq!(case.decision, Some(CaseDecision::Banned));
+        assert_eq!(case.moderator_id, Some(UserId::new(4)));
+        assert!(case.decided_at.is_some());
+
+        // the review timeout firing afterwards must not overwrite the moderator's decision
+        assert!(!database
+            .resolve_case(id, CaseDecision::TimedOut, None)
+            .unwrap());
+        let case = database.case(id).unwrap().unwrap();
+        assert_eq!(case.decision, Some(CaseDecision::Banned));
+        assert!(database.pending_cases().unwrap().is_empty());
+
+        // a failed unmute puts the case back up for review
+        database.reopen_case(id).unwrap();
+        let case = database.case(id).unwrap().unwrap();
+        assert_eq!(case.decision, None);
+        assert_eq!(case.moderator_id, None);
+        assert_eq!(database.pending_cases().unwrap().len(), 1);
+        assert!(database
+            .resolve_case(id, CaseDecision::TimedOut, None)
+            .unwrap());
+
+        assert!(database.case(id + 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn content_fits_in_an_embed_field() {
+        let content = "```rust\nfn main() {}```";
+        let block = code_block(content, EMBED_FIELD_LIMIT);
+        assert_eq!(block.matches("```").count(), 2);
+        assert!(block.starts_with("```\n`\u{200b}"));
+
+        let long = "a".repeat(4000);
+        let block = code_block(&long, EMBED_FIELD_LIMIT);
+        assert_eq!(block.chars().count(), EMBED_FIELD_LIMIT);
+        assert!(block.ends_with("…\n```"));
+        assert_eq!(truncate("short", EMBED_FIELD_LIMIT), "short");
+    }
+
+    #[test]
+    fn media_hashes_round_trip() {
+        let database = Database::open_in_memory().unwrap();
+        let hashes = [u64::MAX, 0x00ff_00ff_00ff_00ff, 1];
+        let id = database
+            .create_case(&NewCase {
+                guild_id: GuildId::new(1),
+                channel_id: ChannelId::new(2),
+                offender_id: UserId::new(3),
+                content: "",
+                reason: "Porn image content",
+                confidence: Some(0.9),
+                media_url: Some("https://media.discordapp.net/a.png"),
+                evidence: None,
+                media_hashes: &hashes,
+                punishment: AppliedPunishment::Timeout,
+            })
+            .unwrap();
+        assert_eq!(database.case(id).unwrap().unwrap().media_hashes, hashes);
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use nsfw::Model;
+use serde::Serialize;
+
+use crate::image_detection::{classify_frames, classify_gif, classify_image, video_frames, Scores};
+
+/// How many video frames are decoded before they're classified, keeps memory use down.
+const VIDEO_BATCH: usize = 30;
+
+/// How `classify` prints its results.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// Kind of media, picked from the file extension.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Image,
+    Gif,
+    Video,
+}
+
+impl MediaKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Gif => "gif",
+            MediaKind::Video => "video",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match extension.as_str() {
+            "gif" => MediaKind::Gif,
+            "mp4" | "webm" | "mov" | "mkv" | "avi" => MediaKind::Video,
+            _ => MediaKind::Image,
+        }
+    }
+}
+
+/// Scores of a classified file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileScores {
+    pub path: String,
+    pub kind: MediaKind,
+    pub frames: Vec<Scores>,
+    pub average: Scores,
+}
+
+/// Classifies a local image, gif or video the same way the nsfw detector classifies
+/// attachments.
+pub async fn classify_file(model: &Model, path: &Path) -> anyhow::Result<FileScores> {
+    let kind = MediaKind::from_path(path);
+    let classifications = match kind {
+        MediaKind::Image => vec![classify_image(model, &fs::read(path)?)?],
+        MediaKind::Gif => classify_gif(model, &fs::read(path)?)?,
+        MediaKind::Video => {
+            if !path.is_file() {
+                bail!("{} is not a file", path.display());
+            }
+            ffmpeg_next::init().context("Failed to initialize ffmpeg")?;
+            let mut stream = video_frames(path.to_string_lossy().into_owned(), None);
+            let mut classifications = vec![];
+            let mut batch = vec![];
+            while let Some(frame) = stream.recv().await {
+                batch.push(frame.to_rgba8());
+                if batch.len() >= VIDEO_BATCH {
+                    classifications.extend(classify_frames(model, std::mem::take(&mut batch)));
+                }
+            }
+            classifications.extend(classify_frames(model, batch));
+            classifications
+        }
+    };
+    if classifications.is_empty() {
+        bail!("no frames of {} could be classified", path.display());
+    }
+    let frames = classifications
+        .iter()
+        .map(|frame| Scores::from_classifications(frame))
+        .collect::<Vec<_>>();
+    Ok(FileScores {
+        path: path.display().to_string(),
+        kind,
+        average: Scores::average(&frames),
+        frames,
+    })
+}
+
+/// One table per file with a row per frame and the average, images only get one row.
+pub fn table(files: &[FileScores]) -> String {
+    let mut table = String::new();
+    for file in files {
+        let _ = writeln!(table, "{} ({})", file.path, file.kind.as_str());
+        let _ = write!(table, "{:<8}", "frame");
+        for name in Scores::NAMES {
+            let _ = write!(table, "{name:>10}");
+        }
+        table.push('\n');
+        let mut row = |label: String, scores: &Scores| {
+            let _ = write!(table, "{label:<8}");
+            for value in scores.values() {
+                let _ = write!(table, "{value:>10.4}");
+            }
+            table.push('\n');
+        };
+        for (index, frame) in file.frames.iter().enumerate() {
+            row((index + 1).to_string(), frame);
+        }
+        if file.frames.len() > 1 {
+            row("average".to_string(), &file.average);
+        }
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(porn: f32, neutral: f32) -> Scores {
+        Scores {
+            porn,
+            neutral,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn media_kind_from_extension() {
+        assert_eq!(MediaKind::from_path(Path::new("a/cat.GIF")), MediaKind::Gif);
+        assert_eq!(
+            MediaKind::from_path(Path::new("clip.webm")),
+            MediaKind::Video
+        );
+        assert_eq!(
+            MediaKind::from_path(Path::new("photo.jpg")),
+            MediaKind::Image
+        );
+        assert_eq!(
+            MediaKind::from_path(Path::new("no_extension")),
+            MediaKind::Image
+        );
+    }
+
+    #[test]
+    fn prints_frames_and_average() {
+        let frames = vec![scores(0.75, 0.25), scores(0.25, 0.75)];
+        let file = FileScores {
+            path: "clip.gif".to_string(),
+            kind: MediaKind::Gif,
+            average: Scores::average(&frames),
+            frames,
+        };
+        assert_eq!(file.average, scores(0.5, 0.5));
+        assert_eq!(
+            table(&[file.clone()]),
+            "clip.gif (gif)\n\
+             frame     drawings    hentai   neutral      porn      sexy\n\
+             1           0.0000    0.0000    0.2500    0.7500    0.0000\n\
+             2           0.0000    0.0000    0.7500    0.2500    0.0000\n\
+             average     0.0000    0.0000    0.5000    0.5000    0.0000\n\n"
+        );
+        let json: serde_json::Value = serde_json::to_value(&file).unwrap();
+        assert_eq!(json["kind"], "gif");
+        assert_eq!(json["frames"][1]["porn"], 0.25);
+    }
+}
@@ -8,11 +8,18 @@ use futures::StreamExt;
 use itertools::Itertools;
 use log::error;
 use log::info;
+use poise::CreateReply;
 use serenity::all::{ChannelId, Http};
 
+use crate::cases::CaseId;
+use crate::nsfw_model;
 use crate::{Error, PotatoContext};
 use anyhow::anyhow;
 
+pub mod allow_list;
+pub mod config;
+pub mod domains;
+
 #[poise::command(
     slash_command,
     prefix_command,
@@ -70,6 +77,85 @@ pub async fn purge(
     Ok(())
 }
 
+/// Looks up a recorded moderation case
+#[poise::command(
+    slash_command,
+    prefix_command,
+    default_member_permissions = "MODERATE_MEMBERS"
+)]
+pub async fn case(
+    ctx: PotatoContext<'_>,
+    #[description = "Case number shown on the mod channel embed"] id: CaseId,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    match ctx.data().database.case(id)? {
+        Some(case) if case.guild_id == guild_id => {
+            ctx.send(CreateReply::default().embed(case.embed()).ephemeral(true))
+                .await?;
+        }
+        _ => {
+            ctx.send(
+                CreateReply::default()
+                    .content(format!("No case #{id} found"))
+                    .ephemeral(true),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Stops removing images that were blocked from a case
+#[poise::command(slash_command, default_member_permissions = "MODERATE_MEMBERS")]
+pub async fn unblock_image(
+    ctx: PotatoContext<'_>,
+    #[description = "Case the image was blocked from"] case: CaseId,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let removed = ctx.data().database.unblock_case_images(guild_id, case)?;
+    let text = if removed == 0 {
+        format!("No images were blocked from case #{case}")
+    } else {
+        info!(
+            "{} unblocked the images from case {case}",
+            ctx.author().name
+        );
+        format!("Unblocked the images from case #{case}")
+    };
+    ctx.send(CreateReply::default().content(text).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+/// Loads the nsfw model again from its file, replace the file first to swap in a new model
+#[poise::command(slash_command, default_member_permissions = "ADMINISTRATOR")]
+pub async fn reload_model(ctx: PotatoContext<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let data = ctx.data();
+    let (model, source) = (data.nsfw_model.clone(), data.model_source.clone());
+    // the model is global, so this only ever reads the file the bot was started with
+    let result = tokio::task::spawn_blocking(move || nsfw_model::reload(&model, &source)).await?;
+    match result {
+        Ok(()) => {
+            info!(
+                "{} reloaded the nsfw model from {}",
+                ctx.author().name,
+                data.model_source
+            );
+            ctx.reply(format!(
+                "Reloaded the nsfw model from {}",
+                data.model_source
+            ))
+            .await?;
+        }
+        Err(e) => {
+            error!("Failed to reload the nsfw model: {e:#}");
+            ctx.reply(format!("Kept the current model: {e:#}")).await?;
+        }
+    }
+    Ok(())
+}
+
 async fn search_channel<'a>(
     http: &'a Http,
     channel_id: ChannelId,
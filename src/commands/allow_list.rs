@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use poise::CreateReply;
+use serenity::all::{CreateEmbed, User};
+
+use super::config::reply;
+use crate::allow_list::{AllowListDuration, AllowListEntry};
+use crate::cases::truncate;
+use crate::{Error, PotatoContext};
+
+const MAX_LISTED: usize = 25;
+/// Keeps a full list inside the 4096 character embed description, same as `max_length` on `add`.
+const MAX_REASON_CHARS: usize = 80;
+
+/// Manage members that skip every check
+#[poise::command(
+    slash_command,
+    guild_only,
+    default_member_permissions = "MODERATE_MEMBERS",
+    subcommands("add", "remove", "list"),
+    subcommand_required
+)]
+pub async fn allowlist(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Allowlists a member for a while, or permanently
+#[poise::command(slash_command)]
+async fn add(
+    ctx: PotatoContext<'_>,
+    #[description = "Member to allowlist"] user: User,
+    #[description = "How long, e.g. 30m, 12h, 1d, 2w or permanent"] duration: String,
+    #[description = "Why they are trusted"]
+    #[max_length = 80]
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let duration = match AllowListDuration::parse(&duration) {
+        Ok(duration) => duration,
+        Err(problem) => return reply(ctx, problem).await,
+    };
+    let now = Utc::now();
+    let expires_at = match duration.expires_at(now) {
+        Ok(expires_at) => expires_at,
+        Err(problem) => return reply(ctx, problem).await,
+    };
+    let entry = AllowListEntry {
+        guild_id,
+        user_id: user.id,
+        expires_at,
+        // a full list only fits in its embed if every stored reason does
+        reason: reason.map(|reason| truncate(&reason, MAX_REASON_CHARS)),
+        granted_by: ctx.author().id,
+        created_at: now,
+    };
+    ctx.data().database.grant_allow_list(&entry)?;
+    reply(
+        ctx,
+        format!("Allowlisted {} {}", user, describe_expiry(&entry)),
+    )
+    .await
+}
+
+/// Removes a member from the allow list
+#[poise::command(slash_command)]
+async fn remove(
+    ctx: PotatoContext<'_>,
+    #[description = "Member to remove"] user: User,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if ctx.data().database.revoke_allow_list(guild_id, user.id)? {
+        reply(ctx, format!("Removed {} from the allow list", user)).await
+    } else {
+        reply(ctx, format!("{} is not on the allow list", user)).await
+    }
+}
+
+/// Shows everyone on the allow list
+#[poise::command(slash_command)]
+async fn list(ctx: PotatoContext<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let now = Utc::now();
+    let entries = ctx
+        .data()
+        .database
+        .allow_list(guild_id)?
+        .into_iter()
+        .filter(|entry| entry.is_active(now))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return reply(ctx, "The allow list is empty").await;
+    }
+    // keep well below discord's embed description limit
+    let mut description = entries
+        .iter()
+        .take(MAX_LISTED)
+        .map(|entry| {
+            let reason = entry.reason.as_deref().unwrap_or("no reason given");
+            format!(
+                "<@{}> {} - {} (by <@{}>)",
+                entry.user_id,
+                describe_expiry(entry),
+                reason,
+                entry.granted_by
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if entries.len() > MAX_LISTED {
+        description += &format!("\n...and {} more", entries.len() - MAX_LISTED);
+    }
+    let embed = CreateEmbed::new()
+        .title("Allow list")
+        .description(description);
+    ctx.send(CreateReply::default().embed(embed).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+fn describe_expiry(entry: &AllowListEntry) -> String {
+    match entry.expires_at {
+        Some(expires_at) => format!("until <t:{}:R>", expires_at.timestamp()),
+        None => "permanently".to_string(),
+    }
+}
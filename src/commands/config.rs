@@ -0,0 +1,618 @@
+use anyhow::anyhow;
+use poise::CreateReply;
+use serenity::all::{ChannelType, CreateEmbed, GuildChannel, GuildId, Role};
+
+use crate::guild_config::{
+    GuildConfig, NsfwMode, NsfwPolicy, PunishmentMode, ScoreThresholds, TyposquatDistance,
+    MAX_TIMEOUT_MINUTES,
+};
+use crate::{Error, PotatoContext};
+
+#[derive(Debug, Copy, Clone, poise::ChoiceParameter)]
+pub enum NsfwCategory {
+    Hentai,
+    Porn,
+    Sexy,
+    #[name = "Gif/video average"]
+    Average,
+}
+
+#[derive(Debug, Copy, Clone, poise::ChoiceParameter)]
+pub enum SignalKind {
+    #[name = "Blocked domain"]
+    BlockedDomain,
+    #[name = "Known phishing domain"]
+    KnownPhishingDomain,
+    #[name = "Lookalike characters"]
+    Homoglyph,
+    #[name = "Fake gift link"]
+    FakeGift,
+    #[name = "Misspelled brand"]
+    Lookalike,
+    #[name = "Masked link mismatch"]
+    MaskedLink,
+    #[name = "@everyone mention"]
+    EveryoneMention,
+    #[name = "New account"]
+    NewAccount,
+    #[name = "First message"]
+    FirstMessage,
+}
+
+#[derive(Debug, Copy, Clone, poise::ChoiceParameter)]
+pub enum DetectorKind {
+    Phishing,
+    Nsfw,
+}
+
+/// Loads the guild's config, applies `change` and saves the result.
+async fn update_config(
+    ctx: PotatoContext<'_>,
+    change: impl FnOnce(&mut GuildConfig),
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let mut config = ctx.data().guild_config(guild_id);
+    change(&mut config);
+    ctx.data().save_guild_config(guild_id, config)?;
+    reply(ctx, "Config updated").await
+}
+
+pub(super) async fn reply(ctx: PotatoContext<'_>, text: impl Into<String>) -> Result<(), Error> {
+    ctx.send(CreateReply::default().content(text).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+fn ensure_role(ctx: PotatoContext<'_>, guild_id: GuildId, role: &Role) -> Result<(), String> {
+    let exists = ctx
+        .guild()
+        .map(|guild| guild.roles.contains_key(&role.id))
+        .unwrap_or_default();
+    if role.guild_id != guild_id || !exists {
+        return Err(format!("Role {} does not exist in this server", role.name));
+    }
+    Ok(())
+}
+
+fn ensure_text_channel(guild_id: GuildId, channel: &GuildChannel) -> Result<(), String> {
+    if channel.guild_id != guild_id {
+        return Err(format!(
+            "Channel {} does not exist in this server",
+            channel.name
+        ));
+    }
+    if !matches!(channel.kind, ChannelType::Text | ChannelType::News) {
+        return Err(format!("{} is not a text channel", channel.name));
+    }
+    Ok(())
+}
+
+/// View or change how the bot moderates this server
+#[poise::command(
+    slash_command,
+    guild_only,
+    default_member_permissions = "MANAGE_GUILD",
+    subcommands(
+        "view",
+        "mod_channel",
+        "mod_role",
+        "muted_role",
+        "punishment",
+        "trusted_role",
+        "nsfw_threshold",
+        "nsfw_channel",
+        "detector",
+        "brand",
+        "typosquat_distance",
+        "blocked_image_distance",
+        "score_thresholds",
+        "signal_weight",
+        "new_account_days"
+    ),
+    subcommand_required
+)]
+pub async fn config(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Shows the current config
+#[poise::command(slash_command)]
+async fn view(ctx: PotatoContext<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let config = ctx.data().guild_config(guild_id);
+    let mention = |id: Option<String>| id.unwrap_or_else(|| "not set".to_string());
+    let trusted_roles = if config.trusted_roles.is_empty() {
+        "none".to_string()
+    } else {
+        config
+            .trusted_roles
+            .iter()
+            .map(|role| format!("<@&{}>", role))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let thresholds = &config.nsfw_thresholds;
+    let threshold = |value: Option<f32>| value.map_or("off".to_string(), |v| v.to_string());
+    let embed = CreateEmbed::new()
+        .title("Moderation config")
+        .field(
+            "Mod channel",
+            mention(config.mod_channel.map(|c| format!("<#{}>", c))),
+            true,
+        )
+        .field(
+            "Mod role",
+            mention(config.mod_role.map(|r| format!("<@&{}>", r))),
+            true,
+        )
+        .field(
+            "Muted role",
+            mention(config.muted_role.map(|r| format!("<@&{}>", r))),
+            true,
+        )
+        .field(
+            "Punishment",
+            match config.punishment {
+                PunishmentMode::MutedRole => "muted role".to_string(),
+                PunishmentMode::Timeout => format!("{} minute timeout", config.timeout_minutes),
+            },
+            true,
+        )
+        .field("Trusted roles", trusted_roles, false)
+        .field(
+            "Protected brands",
+            if config.protected_brands.is_empty() {
+                "none".to_string()
+            } else {
+                config.protected_brands.join(", ")
+            },
+            false,
+        )
+        .field(
+            "Typosquat distance",
+            format!(
+                "at most {} edits, one per {} characters",
+                config.typosquat_distance.max, config.typosquat_distance.chars_per_edit
+            ),
+            true,
+        )
+        .field(
+            "Blocked image distance",
+            format!("{} of 64 bits", config.blocked_image_distance),
+            true,
+        )
+        .field(
+            "Score thresholds",
+            format!(
+                "log: {}\nalert: {}\ndelete and mute: {}",
+                config.score_thresholds.log_only,
+                config.score_thresholds.alert,
+                config.score_thresholds.delete_and_mute
+            ),
+            true,
+        )
+        .field(
+            "Detectors",
+            format!(
+                "phishing: {}\nnsfw: {}",
+                config.detectors.phishing, config.detectors.nsfw
+            ),
+            true,
+        )
+        .field(
+            "NSFW thresholds",
+            format!(
+                "hentai: {}\nporn: {}\nsexy: {}\naverage: {}",
+                threshold(thresholds.hentai),
+                threshold(thresholds.porn),
+                threshold(thresholds.sexy),
+                thresholds.average
+            ),
+            true,
+        );
+    let mut policies = config
+        .nsfw_policies
+        .iter()
+        .map(|(channel, policy)| {
+            let thresholds = if policy.thresholds.is_some() {
+                ", own thresholds"
+            } else {
+                ""
+            };
+            format!("<#{}>: {:?}{}", channel, policy.mode, thresholds)
+        })
+        .collect::<Vec<_>>();
+    policies.sort();
+    let mut channels = String::new();
+    for line in policies {
+        // discord rejects embed fields over 1024 characters
+        if channels.len() + line.len() > 1000 {
+            channels += "\n...";
+            break;
+        }
+        if !channels.is_empty() {
+            channels.push('\n');
+        }
+        channels += &line;
+    }
+    let embed = embed.field(
+        "NSFW channels",
+        if channels.is_empty() {
+            "none, age restricted channels are off".to_string()
+        } else {
+            channels
+        },
+        false,
+    );
+    ctx.send(CreateReply::default().embed(embed).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+/// Sets the channel cases are posted to
+#[poise::command(slash_command)]
+async fn mod_channel(
+    ctx: PotatoContext<'_>,
+    #[description = "Text channel for case reviews"] channel: GuildChannel,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if let Err(problem) = ensure_text_channel(guild_id, &channel) {
+        return reply(ctx, problem).await;
+    }
+    update_config(ctx, |config| config.mod_channel = Some(channel.id)).await
+}
+
+/// Sets the role pinged when a case is posted
+#[poise::command(slash_command)]
+async fn mod_role(
+    ctx: PotatoContext<'_>,
+    #[description = "Role to ping, leave empty to not ping anyone"] role: Option<Role>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if let Some(Err(problem)) = role.as_ref().map(|role| ensure_role(ctx, guild_id, role)) {
+        return reply(ctx, problem).await;
+    }
+    update_config(ctx, |config| config.mod_role = role.map(|r| r.id)).await
+}
+
+/// Sets the role given to offenders while their case is reviewed
+#[poise::command(slash_command)]
+async fn muted_role(
+    ctx: PotatoContext<'_>,
+    #[description = "Role that prevents members from talking"] role: Role,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if let Err(problem) = ensure_role(ctx, guild_id, &role) {
+        return reply(ctx, problem).await;
+    }
+    update_config(ctx, |config| config.muted_role = Some(role.id)).await
+}
+
+/// Chooses how offenders are silenced while their case is reviewed
+#[poise::command(slash_command)]
+async fn punishment(
+    ctx: PotatoContext<'_>,
+    #[description = "Muted role or discord's native timeout"] mode: PunishmentMode,
+    #[description = "How long timeouts last in minutes"]
+    #[min = 1]
+    #[max = 40320]
+    timeout_minutes: Option<u32>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if let Some(minutes) = timeout_minutes {
+        if !(1..=MAX_TIMEOUT_MINUTES).contains(&minutes) {
+            return reply(ctx, "Timeouts must be between 1 minute and 28 days").await;
+        }
+    }
+    let mut config = ctx.data().guild_config(guild_id);
+    if mode == PunishmentMode::MutedRole && config.muted_role.is_none() {
+        return reply(ctx, "Set a muted role with /config muted_role first").await;
+    }
+    config.punishment = mode;
+    if let Some(minutes) = timeout_minutes {
+        config.timeout_minutes = minutes;
+    }
+    ctx.data().save_guild_config(guild_id, config)?;
+    reply(ctx, "Config updated").await
+}
+
+/// Manage roles that are never checked
+#[poise::command(
+    slash_command,
+    subcommands("trusted_role_add", "trusted_role_remove"),
+    subcommand_required
+)]
+async fn trusted_role(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Stops checking members with this role
+#[poise::command(slash_command, rename = "add")]
+async fn trusted_role_add(
+    ctx: PotatoContext<'_>,
+    #[description = "Role to trust"] role: Role,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if let Err(problem) = ensure_role(ctx, guild_id, &role) {
+        return reply(ctx, problem).await;
+    }
+    update_config(ctx, |config| {
+        if !config.trusted_roles.contains(&role.id) {
+            config.trusted_roles.push(role.id);
+        }
+    })
+    .await
+}
+
+/// Starts checking members with this role again
+#[poise::command(slash_command, rename = "remove")]
+async fn trusted_role_remove(
+    ctx: PotatoContext<'_>,
+    #[description = "Role to stop trusting"] role: Role,
+) -> Result<(), Error> {
+    update_config(ctx, |config| config.trusted_roles.retain(|r| *r != role.id)).await
+}
+
+/// Sets the score the NSFW model has to reach before media is flagged
+#[poise::command(slash_command)]
+async fn nsfw_threshold(
+    ctx: PotatoContext<'_>,
+    #[description = "Which score to change"] category: NsfwCategory,
+    #[description = "Between 0 and 1, leave empty to never flag this category"]
+    #[min = 0]
+    #[max = 1]
+    value: Option<f32>,
+) -> Result<(), Error> {
+    if value.map_or(false, |value| !(0.0..=1.0).contains(&value)) {
+        return reply(ctx, "Thresholds must be between 0 and 1").await;
+    }
+    if matches!(category, NsfwCategory::Average) && value.is_none() {
+        return reply(
+            ctx,
+            "The average can't be turned off, turn off the categories instead",
+        )
+        .await;
+    }
+    update_config(ctx, |config| {
+        let thresholds = &mut config.nsfw_thresholds;
+        match category {
+            NsfwCategory::Hentai => thresholds.hentai = value,
+            NsfwCategory::Porn => thresholds.porn = value,
+            NsfwCategory::Sexy => thresholds.sexy = value,
+            NsfwCategory::Average => thresholds.average = value.unwrap_or(thresholds.average),
+        }
+    })
+    .await
+}
+
+/// Manage how the NSFW detector treats channels and categories
+#[poise::command(
+    slash_command,
+    subcommands("nsfw_channel_set", "nsfw_channel_clear"),
+    subcommand_required
+)]
+async fn nsfw_channel(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Sets what happens to NSFW media in a channel, or in every channel of a category
+#[poise::command(slash_command, rename = "set")]
+async fn nsfw_channel_set(
+    ctx: PotatoContext<'_>,
+    #[description = "Channel or category"]
+    #[channel_types("Text", "News", "Forum", "Category")]
+    channel: GuildChannel,
+    #[description = "Off, alert only or delete and mute"] mode: NsfwMode,
+    #[description = "Hentai threshold here, defaults to the server's"]
+    #[min = 0]
+    #[max = 1]
+    hentai: Option<f32>,
+    #[description = "Porn threshold here, defaults to the server's"]
+    #[min = 0]
+    #[max = 1]
+    porn: Option<f32>,
+    #[description = "Sexy threshold here, defaults to the server's"]
+    #[min = 0]
+    #[max = 1]
+    sexy: Option<f32>,
+    #[description = "Gif/video average threshold here, defaults to the server's"]
+    #[min = 0]
+    #[max = 1]
+    average: Option<f32>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    if channel.guild_id != guild_id {
+        return reply(
+            ctx,
+            format!("Channel {} does not exist in this server", channel.name),
+        )
+        .await;
+    }
+    let overrides = [hentai, porn, sexy, average];
+    if overrides
+        .iter()
+        .flatten()
+        .any(|value| !(0.0..=1.0).contains(value))
+    {
+        return reply(ctx, "Thresholds must be between 0 and 1").await;
+    }
+    update_config(ctx, |config| {
+        let thresholds = overrides.iter().any(Option::is_some).then(|| {
+            let mut thresholds = config.nsfw_thresholds.clone();
+            thresholds.hentai = hentai.or(thresholds.hentai);
+            thresholds.porn = porn.or(thresholds.porn);
+            thresholds.sexy = sexy.or(thresholds.sexy);
+            thresholds.average = average.unwrap_or(thresholds.average);
+            thresholds
+        });
+        config
+            .nsfw_policies
+            .insert(channel.id, NsfwPolicy { mode, thresholds });
+    })
+    .await
+}
+
+/// Makes a channel or category follow the server's NSFW settings again
+#[poise::command(slash_command, rename = "clear")]
+async fn nsfw_channel_clear(
+    ctx: PotatoContext<'_>,
+    #[description = "Channel or category"]
+    #[channel_types("Text", "News", "Forum", "Category")]
+    channel: GuildChannel,
+) -> Result<(), Error> {
+    update_config(ctx, |config| {
+        config.nsfw_policies.remove(&channel.id);
+    })
+    .await
+}
+
+/// Turns a detector on or off
+#[poise::command(slash_command)]
+async fn detector(
+    ctx: PotatoContext<'_>,
+    #[description = "Which detector"] detector: DetectorKind,
+    #[description = "Whether it should run"] enabled: bool,
+) -> Result<(), Error> {
+    update_config(ctx, |config| match detector {
+        DetectorKind::Phishing => config.detectors.phishing = enabled,
+        DetectorKind::Nsfw => config.detectors.nsfw = enabled,
+    })
+    .await
+}
+
+/// Manage names that links aren't allowed to misspell or imitate
+#[poise::command(
+    slash_command,
+    subcommands("brand_add", "brand_remove"),
+    subcommand_required
+)]
+async fn brand(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Protects a name from misspelled and lookalike links
+#[poise::command(slash_command, rename = "add")]
+async fn brand_add(
+    ctx: PotatoContext<'_>,
+    #[description = "Domain name without the ending, e.g. steam"] name: String,
+) -> Result<(), Error> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return reply(ctx, "Brands are a single name like `steam`, without dots").await;
+    }
+    update_config(ctx, |config| {
+        if !config.protected_brands.contains(&name) {
+            config.protected_brands.push(name);
+        }
+    })
+    .await
+}
+
+/// Stops protecting a name
+#[poise::command(slash_command, rename = "remove")]
+async fn brand_remove(
+    ctx: PotatoContext<'_>,
+    #[description = "Name to stop protecting"] name: String,
+) -> Result<(), Error> {
+    let name = name.trim().to_lowercase();
+    update_config(ctx, |config| config.protected_brands.retain(|b| *b != name)).await
+}
+
+/// Sets how many typos a link may have and still count as a brand
+#[poise::command(slash_command)]
+async fn typosquat_distance(
+    ctx: PotatoContext<'_>,
+    #[description = "Most edits for any brand, 0 turns typo checks off"]
+    #[min = 0]
+    #[max = 10]
+    max: u32,
+    #[description = "Allow one edit per this many characters of the brand, 0 to always allow the most"]
+    #[min = 0]
+    #[max = 20]
+    chars_per_edit: u32,
+) -> Result<(), Error> {
+    update_config(ctx, |config| {
+        config.typosquat_distance = TyposquatDistance {
+            max: max.min(10),
+            chars_per_edit: chars_per_edit.min(20),
+        }
+    })
+    .await
+}
+
+/// Sets how different an image may be from a blocked one and still be removed
+#[poise::command(slash_command)]
+async fn blocked_image_distance(
+    ctx: PotatoContext<'_>,
+    #[description = "Bits of the image hash that may differ, 0 only matches exact copies"]
+    #[max = 16]
+    distance: u32,
+) -> Result<(), Error> {
+    update_config(ctx, |config| {
+        config.blocked_image_distance = distance.min(16)
+    })
+    .await
+}
+
+/// Sets the scores phishing messages need to be logged, reported or removed
+#[poise::command(slash_command)]
+async fn score_thresholds(
+    ctx: PotatoContext<'_>,
+    #[description = "Only write the message to the bot's log"] log_only: u32,
+    #[description = "Report the message without removing it"] alert: u32,
+    #[description = "Delete the message and mute the author"] delete_and_mute: u32,
+) -> Result<(), Error> {
+    if log_only > alert || alert > delete_and_mute {
+        return reply(
+            ctx,
+            "Thresholds must go up from log only to alert to delete and mute",
+        )
+        .await;
+    }
+    update_config(ctx, |config| {
+        config.score_thresholds = ScoreThresholds {
+            log_only,
+            alert,
+            delete_and_mute,
+        }
+    })
+    .await
+}
+
+/// Sets how much a phishing signal adds to a message's score
+#[poise::command(slash_command)]
+async fn signal_weight(
+    ctx: PotatoContext<'_>,
+    #[description = "Which signal"] signal: SignalKind,
+    #[description = "Points it adds, 0 ignores it"]
+    #[max = 1000]
+    weight: u32,
+) -> Result<(), Error> {
+    update_config(ctx, |config| {
+        let weights = &mut config.signal_weights;
+        let field = match signal {
+            SignalKind::BlockedDomain => &mut weights.blocked_domain,
+            SignalKind::KnownPhishingDomain => &mut weights.known_phishing_domain,
+            SignalKind::Homoglyph => &mut weights.homoglyph,
+            SignalKind::FakeGift => &mut weights.fake_gift,
+            SignalKind::Lookalike => &mut weights.lookalike,
+            SignalKind::MaskedLink => &mut weights.masked_link,
+            SignalKind::EveryoneMention => &mut weights.everyone_mention,
+            SignalKind::NewAccount => &mut weights.new_account,
+            SignalKind::FirstMessage => &mut weights.first_message,
+        };
+        *field = weight.min(1000);
+    })
+    .await
+}
+
+/// Sets how young an account has to be to count as new
+#[poise::command(slash_command)]
+async fn new_account_days(
+    ctx: PotatoContext<'_>,
+    #[description = "Age in days"]
+    #[max = 3650]
+    days: u32,
+) -> Result<(), Error> {
+    update_config(ctx, |config| config.new_account_days = days.min(3650)).await
+}
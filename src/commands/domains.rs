@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+use poise::CreateReply;
+use serenity::all::CreateEmbed;
+
+use super::config::reply;
+use crate::guild_config::GuildConfig;
+use crate::phishing::parse_domain_pattern;
+use crate::{Error, PotatoContext};
+
+#[derive(Debug, Copy, Clone, poise::ChoiceParameter)]
+pub enum DomainList {
+    #[name = "Allow list"]
+    Allowed,
+    #[name = "Block list"]
+    Blocked,
+}
+
+impl DomainList {
+    fn entries(self, config: &mut GuildConfig) -> &mut Vec<String> {
+        match self {
+            DomainList::Allowed => &mut config.allowed_domains,
+            DomainList::Blocked => &mut config.blocked_domains,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DomainList::Allowed => "allow list",
+            DomainList::Blocked => "block list",
+        }
+    }
+}
+
+/// Manage domains the phishing rules always allow or always flag
+#[poise::command(
+    slash_command,
+    guild_only,
+    default_member_permissions = "MODERATE_MEMBERS",
+    subcommands("add", "remove", "list"),
+    subcommand_required
+)]
+pub async fn domains(_: PotatoContext<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Adds a domain, *.example.com also covers every subdomain
+#[poise::command(slash_command)]
+async fn add(
+    ctx: PotatoContext<'_>,
+    #[description = "Which list"] list: DomainList,
+    #[description = "Domain like example.com or *.example.com"] domain: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let domain = match parse_domain_pattern(&domain) {
+        Ok(domain) => domain,
+        Err(problem) => return reply(ctx, problem).await,
+    };
+    let mut config = ctx.data().guild_config(guild_id);
+    let entries = list.entries(&mut config);
+    if entries.contains(&domain) {
+        return reply(
+            ctx,
+            format!("`{}` is already on the {}", domain, list.name()),
+        )
+        .await;
+    }
+    entries.push(domain.clone());
+    ctx.data().save_guild_config(guild_id, config)?;
+    reply(ctx, format!("Added `{}` to the {}", domain, list.name())).await
+}
+
+/// Removes a domain
+#[poise::command(slash_command)]
+async fn remove(
+    ctx: PotatoContext<'_>,
+    #[description = "Which list"] list: DomainList,
+    #[description = "Entry to remove, exactly as shown by /domains list"] domain: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let domain = parse_domain_pattern(&domain).unwrap_or(domain);
+    let mut config = ctx.data().guild_config(guild_id);
+    let entries = list.entries(&mut config);
+    let before = entries.len();
+    entries.retain(|entry| *entry != domain);
+    if entries.len() == before {
+        return reply(ctx, format!("`{}` is not on the {}", domain, list.name())).await;
+    }
+    ctx.data().save_guild_config(guild_id, config)?;
+    reply(
+        ctx,
+        format!("Removed `{}` from the {}", domain, list.name()),
+    )
+    .await
+}
+
+/// Shows both domain lists
+#[poise::command(slash_command)]
+async fn list(ctx: PotatoContext<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or(anyhow!("No guild provided"))?;
+    let config = ctx.data().guild_config(guild_id);
+    let show = |entries: &[String]| {
+        if entries.is_empty() {
+            "empty".to_string()
+        } else {
+            // entries are short, but keep below discord's 1024 character field limit
+            let mut shown = String::new();
+            for (i, entry) in entries.iter().enumerate() {
+                let line = format!("`{}`\n", entry);
+                if shown.len() + line.len() > 1000 {
+                    shown += &format!("...and {} more", entries.len() - i);
+                    break;
+                }
+                shown += &line;
+            }
+            shown
+        }
+    };
+    let embed = CreateEmbed::new()
+        .title("Domain lists")
+        .field("Allowed", show(&config.allowed_domains), true)
+        .field("Blocked", show(&config.blocked_domains), true);
+    ctx.send(CreateReply::default().embed(embed).ephemeral(true))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::Connection;
+
+/// Schema migrations, applied in order. The index of the last applied migration is tracked with
+/// sqlite's `user_version` pragma, so new migrations should only ever be appended here.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE cases (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        guild_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        offender_id INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        confidence REAL,
+        media_url TEXT,
+        created_at TEXT NOT NULL,
+        decision TEXT,
+        moderator_id INTEGER,
+        decided_at TEXT
+    );
+    CREATE INDEX cases_offender ON cases (guild_id, offender_id);
+"#,
+    r#"
+    ALTER TABLE cases ADD COLUMN mod_channel_id INTEGER;
+    ALTER TABLE cases ADD COLUMN mod_message_id INTEGER;
+    ALTER TABLE cases ADD COLUMN evidence_message_id INTEGER;
+    ALTER TABLE cases ADD COLUMN review_expires_at TEXT;
+"#,
+    r#"
+    CREATE TABLE guild_configs (
+        guild_id INTEGER PRIMARY KEY,
+        config TEXT NOT NULL
+    );
+"#,
+    r#"
+    CREATE TABLE allow_list (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        expires_at TEXT,
+        reason TEXT,
+        granted_by INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (guild_id, user_id)
+    );
+"#,
+    r#"
+    ALTER TABLE cases ADD COLUMN punishment TEXT;
+    ALTER TABLE cases ADD COLUMN muted_role_id INTEGER;
+"#,
+    r#"
+    ALTER TABLE cases ADD COLUMN evidence TEXT;
+"#,
+    r#"
+    CREATE TABLE message_authors (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        first_seen TEXT NOT NULL,
+        PRIMARY KEY (guild_id, user_id)
+    );
+"#,
+    r#"
+    ALTER TABLE cases ADD COLUMN media_hashes TEXT;
+    CREATE TABLE blocked_images (
+        guild_id INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        case_id INTEGER,
+        blocked_by INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (guild_id, hash)
+    );
+"#,
+];
+
+/// Local embedded store for everything the bot needs to remember across restarts.
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> rusqlite::Result<Self> {
+        let version: usize =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.pragma_update(None, "user_version", index + 1)?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Locks the connection. Never hold the guard across an await point.
+    pub(crate) fn connection(&self) -> MutexGuard<'_, Connection> {
+        // a panic while holding the lock can't leave sqlite in a bad state, so ignore poisoning
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
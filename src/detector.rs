@@ -0,0 +1,122 @@
+use std::sync::{Arc, RwLock};
+
+use log::{info, warn};
+use poise::serenity_prelude::{self as serenity, Member, Message};
+
+use crate::database::Database;
+use crate::guild_config::GuildConfig;
+use crate::image_detection::ImageChecker;
+use crate::media_fetcher::MediaFetcher;
+use crate::nsfw_model::SharedModel;
+use crate::phishing::PhishingDetector;
+use crate::phishing_feeds::KnownDomains;
+use crate::rules::RuleSet;
+use crate::scoring::Action;
+use crate::RejectionReason;
+
+/// Everything a detector may look at when checking a message.
+pub struct DetectionContext<'a> {
+    pub ctx: &'a serenity::Context,
+    pub message: &'a Message,
+    pub member: &'a Member,
+    pub config: &'a GuildConfig,
+    /// Whether this is the first message the bot has seen from the member in this guild.
+    pub first_message: bool,
+}
+
+/// Proof shown to moderators alongside a verdict.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Evidence {
+    /// Media that gets posted on its own so discord renders a preview of it.
+    Media(String),
+    /// Explanation of what matched.
+    Text(String),
+}
+
+/// Why a detector thinks a message should be removed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verdict {
+    pub reason: RejectionReason,
+    /// How sure the detector is, between 0 and 1, if it can tell.
+    pub confidence: Option<f32>,
+    pub evidence: Option<Evidence>,
+    /// Perceptual hashes of the flagged media's frames, moderators can block them from the case.
+    pub media_hashes: Vec<u64>,
+    pub action: Action,
+}
+
+/// A check that runs against every message from members who aren't trusted.
+#[poise::async_trait]
+pub trait Detector: Send + Sync {
+    /// Name used to turn the detector on and off in a guild's config.
+    fn name(&self) -> &'static str;
+
+    async fn detect(&self, context: &DetectionContext<'_>) -> Option<Verdict>;
+}
+
+/// Builds the detectors named in `order`, they run in that order and the first verdict wins.
+/// Unknown and repeated names are logged and skipped, `nsfw` is the old name of `media`.
+pub fn build_detectors(
+    order: &str,
+    model: SharedModel,
+    database: Arc<Database>,
+    known_domains: Arc<RwLock<KnownDomains>>,
+    fetcher: Arc<MediaFetcher>,
+    rules: Arc<RwLock<RuleSet>>,
+) -> Vec<Box<dyn Detector>> {
+    let mut detectors: Vec<Box<dyn Detector>> = vec![];
+    for name in order.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let name = if name == "nsfw" { "media" } else { name };
+        if detectors.iter().any(|detector| detector.name() == name) {
+            warn!("The {name} detector can only be listed once");
+            continue;
+        }
+        match name {
+            "phishing" => detectors.push(Box::new(PhishingDetector {
+                known_domains: known_domains.clone(),
+                rules: rules.clone(),
+            })),
+            "media" => detectors.push(Box::new(ImageChecker::new(
+                model.clone(),
+                database.clone(),
+                known_domains.clone(),
+                fetcher.clone(),
+            ))),
+            _ => warn!("Unknown detector {name}"),
+        }
+    }
+    detectors
+}
+
+/// Runs the enabled detectors in order and returns the most severe verdict. Detectors after the
+/// first one that wants the message deleted are skipped, on ties the earlier verdict wins.
+pub async fn run_detectors(
+    detectors: &[Box<dyn Detector>],
+    context: &DetectionContext<'_>,
+) -> Option<Verdict> {
+    let mut worst: Option<Verdict> = None;
+    for detector in detectors {
+        if !context.config.detectors.is_enabled(detector.name()) {
+            continue;
+        }
+        let Some(verdict) = detector.detect(context).await else {
+            continue;
+        };
+        info!(
+            "{} flagged message {} ({:?})",
+            detector.name(),
+            context.message.id,
+            verdict.action
+        );
+        if verdict.action == Action::DeleteAndMute {
+            return Some(verdict);
+        }
+        if worst
+            .as_ref()
+            .map_or(true, |worst| verdict.action > worst.action)
+        {
+            worst = Some(verdict);
+        }
+    }
+    worst
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::guild_config::GuildConfig;
+use crate::phishing::evaluate;
+use crate::phishing_feeds::KnownDomains;
+use crate::rules::{RuleInput, RuleSet};
+use crate::SpamReason;
+
+/// One line of a labeled corpus, e.g. `{"content": "free nitro https://...", "spam": true,
+/// "reason": "phishing"}`. The reason is optional, without it any reason counts as correct.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabeledMessage {
+    pub content: String,
+    pub spam: bool,
+    #[serde(default)]
+    pub reason: Option<SpamReason>,
+}
+
+/// Reads a corpus with one [`LabeledMessage`] per line, blank lines are skipped.
+pub fn read_corpus(path: &Path) -> anyhow::Result<Vec<LabeledMessage>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{} line {}", path.display(), index + 1))
+        })
+        .collect()
+}
+
+/// A message the detectors got wrong.
+#[derive(Debug, PartialEq)]
+pub struct Mistake {
+    pub content: String,
+    pub expected: Option<SpamReason>,
+    /// What the detectors flagged it as, and every signal that fired.
+    pub flagged: Option<(SpamReason, Vec<String>)>,
+}
+
+/// How many messages of each reason were labeled, flagged and flagged for the right reason.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReasonCounts {
+    pub labeled: usize,
+    pub flagged: usize,
+    pub correct: usize,
+}
+
+/// Results of running the text detectors over a corpus.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub true_negatives: usize,
+    pub false_negatives: usize,
+    /// Keyed by [`SpamReason`]'s description so the table is sorted.
+    pub reasons: BTreeMap<&'static str, ReasonCounts>,
+    pub false_positive_messages: Vec<Mistake>,
+    pub false_negative_messages: Vec<Mistake>,
+    /// Spam that was flagged, but for a different reason than labeled.
+    pub wrong_reason_messages: Vec<Mistake>,
+}
+
+impl Report {
+    /// Runs every message through the link rules and `rules` with the default guild config,
+    /// the same checks as [`check_is_phishing_link`](crate::phishing::check_is_phishing_link).
+    pub fn run(corpus: &[LabeledMessage], rules: &RuleSet, known: &KnownDomains) -> Self {
+        let config = GuildConfig::default();
+        let mut report = Self::default();
+        for message in corpus {
+            let input = RuleInput {
+                content: &message.content,
+                ..Default::default()
+            };
+            let flagged = evaluate(input, &config, known, rules).map(|evaluation| {
+                let signals = evaluation
+                    .score
+                    .signals
+                    .into_iter()
+                    .map(|signal| format!("+{} {}", signal.weight, signal.description))
+                    .collect::<Vec<_>>();
+                (evaluation.reason, signals)
+            });
+            if let Some(reason) = &message.reason {
+                report.reasons.entry(reason.as_str()).or_default().labeled += 1;
+            }
+            if let Some((reason, _)) = &flagged {
+                let counts = report.reasons.entry(reason.as_str()).or_default();
+                counts.flagged += 1;
+                if message
+                    .reason
+                    .as_ref()
+                    .map_or(false, |label| label == reason)
+                {
+                    counts.correct += 1;
+                }
+            }
+            let mistake = || Mistake {
+                content: message.content.clone(),
+                expected: message.reason.clone(),
+                flagged: flagged.clone(),
+            };
+            match (message.spam, &flagged) {
+                (true, Some((reason, _))) => {
+                    report.true_positives += 1;
+                    if message
+                        .reason
+                        .as_ref()
+                        .map_or(false, |label| label != reason)
+                    {
+                        report.wrong_reason_messages.push(mistake());
+                    }
+                }
+                (true, None) => {
+                    report.false_negatives += 1;
+                    report.false_negative_messages.push(mistake());
+                }
+                (false, Some(_)) => {
+                    report.false_positives += 1;
+                    report.false_positive_messages.push(mistake());
+                }
+                (false, None) => report.true_negatives += 1,
+            }
+        }
+        report
+    }
+
+    /// Share of flagged messages that are spam, `None` if nothing was flagged.
+    pub fn precision(&self) -> Option<f64> {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    /// Share of spam that was flagged, `None` if there's no spam in the corpus.
+    pub fn recall(&self) -> Option<f64> {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+}
+
+fn ratio(part: usize, total: usize) -> Option<f64> {
+    (total > 0).then(|| part as f64 / total as f64)
+}
+
+fn percent(ratio: Option<f64>) -> String {
+    ratio.map_or("n/a".to_string(), |ratio| format!("{:.1}%", ratio * 100.0))
+}
+
+fn write_mistakes(f: &mut fmt::Formatter, title: &str, mistakes: &[Mistake]) -> fmt::Result {
+    if mistakes.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "\n{title} ({})", mistakes.len())?;
+    for mistake in mistakes {
+        writeln!(f, "  {:?}", mistake.content)?;
+        if let Some(expected) = &mistake.expected {
+            writeln!(f, "    labeled: {}", expected.as_str())?;
+        }
+        if let Some((reason, signals)) = &mistake.flagged {
+            writeln!(f, "    flagged: {}", reason.as_str())?;
+            for signal in signals {
+                writeln!(f, "      {signal}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<12}{:>10}{:>14}", "", "flagged", "not flagged")?;
+        writeln!(
+            f,
+            "{:<12}{:>10}{:>14}",
+            "spam", self.true_positives, self.false_negatives
+        )?;
+        writeln!(
+            f,
+            "{:<12}{:>10}{:>14}",
+            "not spam", self.false_positives, self.true_negatives
+        )?;
+        writeln!(
+            f,
+            "\nprecision {}, recall {}",
+            percent(self.precision()),
+            percent(self.recall())
+        )?;
+        if !self.reasons.is_empty() {
+            writeln!(
+                f,
+                "\n{:<30}{:>9}{:>9}{:>9}",
+                "reason", "labeled", "flagged", "correct"
+            )?;
+            for (reason, counts) in &self.reasons {
+                writeln!(
+                    f,
+                    "{:<30}{:>9}{:>9}{:>9}",
+                    reason, counts.labeled, counts.flagged, counts.correct
+                )?;
+            }
+        }
+        write_mistakes(f, "False positives", &self.false_positive_messages)?;
+        write_mistakes(f, "False negatives", &self.false_negative_messages)?;
+        write_mistakes(f, "Wrong reason", &self.wrong_reason_messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_mistakes_and_reasons() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let corpus = read_corpus(&dir.join("phishing_corpus.jsonl")).unwrap();
+        let report = Report::run(&corpus, &RuleSet::builtin(), &KnownDomains::default());
+        assert_eq!(
+            (
+                report.true_positives,
+                report.false_positives,
+                report.true_negatives,
+                report.false_negatives
+            ),
+            (6, 1, 5, 1)
+        );
+        assert_eq!(
+            report.false_positive_messages[0].content,
+            "free pizza in the break room, menu at https://example.com/menu"
+        );
+        assert_eq!(
+            report.false_negative_messages[0].content,
+            "claim your steam gift card at https://steam-gifts.example"
+        );
+        assert_eq!(
+            report.reasons["Misleading URL"],
+            ReasonCounts {
+                labeled: 2,
+                flagged: 2,
+                correct: 2
+            }
+        );
+        assert_eq!(report.precision(), Some(6.0 / 7.0));
+        assert!(report.to_string().starts_with(
+            "               flagged   not flagged\nspam                 6             1\n"
+        ));
+    }
+
+    #[test]
+    fn corpus_errors_name_the_line() {
+        let path = std::env::temp_dir().join(format!("corpus-{}.jsonl", std::process::id()));
+        fs::write(
+            &path,
+            "{\"content\": \"hi\", \"spam\": false}\n\n{\"content\": 1}\n",
+        )
+        .unwrap();
+        let error = read_corpus(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.to_string(), format!("{} line 3", path.display()));
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+
+use log::warn;
+use poise::serenity_prelude::{ChannelId, GuildId, RoleId};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+
+/// Which checks run against messages in a guild.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Detectors {
+    pub phishing: bool,
+    pub nsfw: bool,
+}
+
+impl Detectors {
+    /// Whether the detector with this [`Detector::name`](crate::detector::Detector::name) should
+    /// run. Detectors without a toggle always run, the media detector checks `nsfw` itself.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        match name {
+            "phishing" => self.phishing,
+            _ => true,
+        }
+    }
+}
+
+impl Default for Detectors {
+    fn default() -> Self {
+        Self {
+            phishing: true,
+            // guilds opt in once they've set up channel policies
+            nsfw: false,
+        }
+    }
+}
+
+/// Scores the NSFW model has to reach before an image is flagged, categories without a
+/// threshold are never flagged.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NsfwThresholds {
+    pub hentai: Option<f32>,
+    pub porn: Option<f32>,
+    pub sexy: Option<f32>,
+    /// Average score across the frames of a gif or video.
+    pub average: f32,
+}
+
+impl NsfwThresholds {
+    /// Uses `ML_RANGE` as the threshold of every category when it's set.
+    fn from_env() -> Self {
+        Self::with_ml_range(dotenv::var("ML_RANGE").ok().as_deref())
+    }
+
+    fn with_ml_range(ml_range: Option<&str>) -> Self {
+        let mut thresholds = Self::default();
+        let Some(ml_range) = ml_range.filter(|value| !value.trim().is_empty()) else {
+            return thresholds;
+        };
+        match ml_range.trim().parse::<f32>() {
+            Ok(value) if (0.0..=1.0).contains(&value) => {
+                if value < 0.5 {
+                    // older templates shipped ML_RANGE=0.4 back when it was ignored
+                    warn!("ML_RANGE={value} flags media at low scores, the default is 0.85");
+                }
+                thresholds.hentai = Some(value);
+                thresholds.porn = Some(value);
+                thresholds.sexy = Some(value);
+            }
+            _ => warn!("ML_RANGE must be between 0 and 1, ignoring {ml_range:?}"),
+        }
+        thresholds
+    }
+
+    /// Whether any category can be flagged, media isn't downloaded otherwise.
+    pub fn any_enabled(&self) -> bool {
+        self.hentai.is_some() || self.porn.is_some() || self.sexy.is_some()
+    }
+}
+
+impl Default for NsfwThresholds {
+    fn default() -> Self {
+        Self {
+            hentai: Some(0.85),
+            porn: Some(0.85),
+            sexy: Some(0.85),
+            average: 0.9,
+        }
+    }
+}
+
+/// What the nsfw detector does in a channel.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, poise::ChoiceParameter,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NsfwMode {
+    /// Media isn't checked at all.
+    Off,
+    /// Flagged media is posted to the mod channel and left up.
+    #[name = "Alert only"]
+    Alert,
+    /// Flagged media is deleted and the author muted.
+    #[default]
+    Enforce,
+}
+
+/// NSFW settings for one channel or category.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NsfwPolicy {
+    pub mode: NsfwMode,
+    /// Thresholds used instead of the guild's.
+    pub thresholds: Option<NsfwThresholds>,
+}
+
+/// How far a domain may be from a protected brand and still count as a typosquat.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TyposquatDistance {
+    /// Most edits allowed for any brand.
+    pub max: u32,
+    /// Brands allow one edit for every this many characters, so short brands don't match common
+    /// words and brands shorter than this aren't checked for typos. Zero always allows `max`.
+    pub chars_per_edit: u32,
+}
+
+impl TyposquatDistance {
+    /// Most edits a domain can be away from `brand` and still be flagged.
+    pub fn allowed(&self, brand: &str) -> usize {
+        let max = self.max as usize;
+        if self.chars_per_edit == 0 {
+            return max;
+        }
+        let scaled = brand.chars().count() / self.chars_per_edit as usize;
+        scaled.min(max)
+    }
+}
+
+impl Default for TyposquatDistance {
+    fn default() -> Self {
+        // one typo in brands as long as discord, whole words like twitter or glitch are a few
+        // edits from short brands
+        Self {
+            max: 1,
+            chars_per_edit: 7,
+        }
+    }
+}
+
+/// How much each phishing signal adds to a message's score.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignalWeights {
+    pub blocked_domain: u32,
+    pub known_phishing_domain: u32,
+    pub homoglyph: u32,
+    pub fake_gift: u32,
+    /// Typos of a protected brand, or a brand used as a subdomain.
+    pub lookalike: u32,
+    /// Link text showing a different url than the link goes to.
+    pub masked_link: u32,
+    pub everyone_mention: u32,
+    pub new_account: u32,
+    pub first_message: u32,
+}
+
+impl Default for SignalWeights {
+    fn default() -> Self {
+        Self {
+            blocked_domain: 100,
+            known_phishing_domain: 100,
+            homoglyph: 80,
+            fake_gift: 70,
+            lookalike: 60,
+            masked_link: 50,
+            everyone_mention: 25,
+            new_account: 15,
+            first_message: 10,
+        }
+    }
+}
+
+/// Scores a message has to reach for each [`Action`](crate::scoring::Action).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoreThresholds {
+    pub log_only: u32,
+    pub alert: u32,
+    pub delete_and_mute: u32,
+}
+
+impl Default for ScoreThresholds {
+    fn default() -> Self {
+        Self {
+            log_only: 20,
+            alert: 40,
+            delete_and_mute: 60,
+        }
+    }
+}
+
+/// How offenders are silenced while their case is reviewed.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, poise::ChoiceParameter,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PunishmentMode {
+    /// Give them the guild's muted role.
+    #[default]
+    #[name = "Muted role"]
+    MutedRole,
+    /// Use discord's native member timeout.
+    #[name = "Discord timeout"]
+    Timeout,
+}
+
+/// Discord doesn't allow timeouts longer than 28 days.
+pub const MAX_TIMEOUT_MINUTES: u32 = 28 * 24 * 60;
+
+/// Per guild moderation settings.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildConfig {
+    /// Where cases get posted for review.
+    pub mod_channel: Option<ChannelId>,
+    /// Role pinged when a case is posted.
+    pub mod_role: Option<RoleId>,
+    /// Role given to offenders while their case is reviewed.
+    pub muted_role: Option<RoleId>,
+    pub punishment: PunishmentMode,
+    /// How long offenders are timed out for when using [`PunishmentMode::Timeout`].
+    pub timeout_minutes: u32,
+    /// Members with any of these roles are never checked.
+    pub trusted_roles: Vec<RoleId>,
+    pub detectors: Detectors,
+    pub nsfw_thresholds: NsfwThresholds,
+    /// NSFW settings of channels and categories, threads use their channel's.
+    pub nsfw_policies: HashMap<ChannelId, NsfwPolicy>,
+    /// Lowercase names that links aren't allowed to imitate with typos or lookalike characters.
+    pub protected_brands: Vec<String>,
+    pub typosquat_distance: TyposquatDistance,
+    /// Most bits an image's hash may differ from a blocked image's and still be removed.
+    pub blocked_image_distance: u32,
+    /// Domains the phishing rules never flag, `*.example.com` also covers subdomains.
+    pub allowed_domains: Vec<String>,
+    /// Domains that are always flagged, same format as `allowed_domains`.
+    pub blocked_domains: Vec<String>,
+    pub signal_weights: SignalWeights,
+    pub score_thresholds: ScoreThresholds,
+    /// Accounts younger than this count as new when scoring messages.
+    pub new_account_days: u32,
+}
+
+impl Default for GuildConfig {
+    fn default() -> Self {
+        Self {
+            mod_channel: None,
+            mod_role: None,
+            muted_role: None,
+            punishment: PunishmentMode::default(),
+            timeout_minutes: 24 * 60,
+            trusted_roles: vec![],
+            detectors: Detectors::default(),
+            nsfw_thresholds: NsfwThresholds::default(),
+            nsfw_policies: HashMap::new(),
+            protected_brands: ["discord", "steamcommunity", "steampowered", "twitch"]
+                .iter()
+                .map(|brand| brand.to_string())
+                .collect(),
+            typosquat_distance: TyposquatDistance::default(),
+            blocked_image_distance: 6,
+            allowed_domains: vec![],
+            blocked_domains: vec![],
+            signal_weights: SignalWeights::default(),
+            score_thresholds: ScoreThresholds::default(),
+            new_account_days: 7,
+        }
+    }
+}
+
+fn env_id(key: &str) -> Option<u64> {
+    dotenv::var(key).ok()?.trim().parse().ok()
+}
+
+impl GuildConfig {
+    /// What the nsfw detector does in a channel and with which thresholds. `channels` goes from
+    /// the message's channel up to its category and the first one with a policy wins. Channels
+    /// discord marks as age restricted are off unless they have a policy.
+    pub fn nsfw_policy(
+        &self,
+        channels: &[ChannelId],
+        age_restricted: bool,
+    ) -> (NsfwMode, &NsfwThresholds) {
+        match channels.iter().find_map(|id| self.nsfw_policies.get(id)) {
+            Some(policy) => (
+                policy.mode,
+                policy.thresholds.as_ref().unwrap_or(&self.nsfw_thresholds),
+            ),
+            None if age_restricted => (NsfwMode::Off, &self.nsfw_thresholds),
+            None => (NsfwMode::Enforce, &self.nsfw_thresholds),
+        }
+    }
+
+    /// Config for a guild that was never set up. Only the legacy single-server guild in `GUILD_ID`
+    /// gets the `.env` settings, any other guild would be sent to that server's channels.
+    pub fn fallback(guild_id: GuildId) -> Self {
+        let legacy_guild = env_id("GUILD_ID").filter(|&id| id != 0).map(GuildId::new);
+        Self::fallback_for(guild_id, legacy_guild)
+    }
+
+    /// Legacy `.env` settings that are set but ignored because `GUILD_ID` isn't.
+    pub fn ignored_env() -> Vec<&'static str> {
+        if env_id("GUILD_ID").is_some_and(|id| id != 0) {
+            return vec![];
+        }
+        ["MOD_CHANNEL", "MOD_ROLE", "MUTED_ROLE", "TRUSTED_ROLES"]
+            .into_iter()
+            .filter(|key| dotenv::var(key).is_ok_and(|value| !value.trim().is_empty()))
+            .collect()
+    }
+
+    fn fallback_for(guild_id: GuildId, legacy_guild: Option<GuildId>) -> Self {
+        if legacy_guild == Some(guild_id) {
+            Self::from_env()
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Config built from the legacy single-server `.env` variables so existing deployments keep
+    /// working.
+    pub fn from_env() -> Self {
+        Self {
+            mod_channel: env_id("MOD_CHANNEL").map(ChannelId::new),
+            mod_role: env_id("MOD_ROLE").map(RoleId::new),
+            muted_role: env_id("MUTED_ROLE").map(RoleId::new),
+            trusted_roles: dotenv::var("TRUSTED_ROLES")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|role| role.trim().parse().ok())
+                .map(RoleId::new)
+                .collect(),
+            detectors: Detectors {
+                // used to be a global switch, now it only applies to the `GUILD_ID` guild
+                nsfw: dotenv::var("NSFW_FILTER_ENABLED").map_or(false, |v| v.contains("true")),
+                ..Default::default()
+            },
+            nsfw_thresholds: NsfwThresholds::from_env(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Database {
+    pub fn guild_config(&self, guild_id: GuildId) -> anyhow::Result<Option<GuildConfig>> {
+        let config: Option<String> = self
+            .connection()
+            .query_row(
+                "SELECT config FROM guild_configs WHERE guild_id = ?1",
+                [guild_id.get()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(config.map(|c| serde_json::from_str(&c)).transpose()?)
+    }
+
+    /// The stored config for a guild, falling back to [`GuildConfig::fallback`].
+    pub fn guild_config_or_default(&self, guild_id: GuildId) -> anyhow::Result<GuildConfig> {
+        Ok(self
+            .guild_config(guild_id)?
+            .unwrap_or_else(|| GuildConfig::fallback(guild_id)))
+    }
+
+    pub fn guild_configs(&self) -> anyhow::Result<HashMap<GuildId, GuildConfig>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT guild_id, config FROM guild_configs")?;
+        let rows = statement.query_map([], |row| {
+            Ok((GuildId::new(row.get(0)?), row.get::<_, String>(1)?))
+        })?;
+        let mut configs = HashMap::new();
+        for row in rows {
+            let (guild_id, config) = row?;
+            configs.insert(guild_id, serde_json::from_str(&config)?);
+        }
+        Ok(configs)
+    }
+
+    pub fn save_guild_config(&self, guild_id: GuildId, config: &GuildConfig) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT INTO guild_configs (guild_id, config) VALUES (?1, ?2)
+             ON CONFLICT (guild_id) DO UPDATE SET config = excluded.config",
+            params![guild_id.get(), serde_json::to_string(config)?],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trip() {
+        let database = Database::open_in_memory().unwrap();
+        let guild = GuildId::new(1);
+        assert_eq!(database.guild_config(guild).unwrap(), None);
+
+        let mut config = GuildConfig {
+            mod_channel: Some(ChannelId::new(2)),
+            trusted_roles: vec![RoleId::new(3), RoleId::new(4)],
+            ..Default::default()
+        };
+        config.detectors.nsfw = false;
+        database.save_guild_config(guild, &config).unwrap();
+        assert_eq!(database.guild_config(guild).unwrap(), Some(config.clone()));
+
+        config.muted_role = Some(RoleId::new(5));
+        database.save_guild_config(guild, &config).unwrap();
+        let configs = database.guild_configs().unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[&guild], config);
+    }
+
+    #[test]
+    fn nsfw_toggle_leaves_the_media_detector_running() {
+        let detectors = Detectors {
+            phishing: false,
+            nsfw: false,
+        };
+        assert!(!detectors.is_enabled("phishing"));
+        assert!(detectors.is_enabled("media"));
+    }
+
+    #[test]
+    fn only_the_legacy_guild_falls_back_to_env() {
+        let other = GuildConfig::fallback_for(GuildId::new(2), Some(GuildId::new(1)));
+        assert_eq!(other, GuildConfig::default());
+        let unset = GuildConfig::fallback_for(GuildId::new(1), None);
+        assert_eq!(unset, GuildConfig::default());
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let config: GuildConfig = serde_json::from_str(r#"{"mod_channel": "2"}"#).unwrap();
+        assert_eq!(config.mod_channel, Some(ChannelId::new(2)));
+        assert_eq!(config.detectors, Detectors::default());
+        assert_eq!(config.punishment, PunishmentMode::MutedRole);
+        assert_eq!(config.timeout_minutes, 24 * 60);
+        assert_eq!(config.nsfw_thresholds, NsfwThresholds::default());
+        assert_eq!(config.typosquat_distance, TyposquatDistance::default());
+        assert_eq!(config.score_thresholds, ScoreThresholds::default());
+    }
+
+    #[test]
+    fn nsfw_categories_can_be_disabled() {
+        let config: GuildConfig =
+            serde_json::from_str(r#"{"nsfw_thresholds": {"porn": 0.5, "sexy": null}}"#).unwrap();
+        let thresholds = config.nsfw_thresholds;
+        assert_eq!(thresholds.hentai, Some(0.85));
+        assert_eq!(thresholds.porn, Some(0.5));
+        assert_eq!(thresholds.sexy, None);
+        assert!(thresholds.any_enabled());
+        let off = NsfwThresholds {
+            hentai: None,
+            porn: None,
+            sexy: None,
+            average: 0.9,
+        };
+        assert!(!off.any_enabled());
+    }
+
+    #[test]
+    fn nsfw_policy_falls_back_to_parents() {
+        let (thread, channel, category, other) = (
+            ChannelId::new(1),
+            ChannelId::new(2),
+            ChannelId::new(3),
+            ChannelId::new(4),
+        );
+        let strict = NsfwThresholds {
+            porn: Some(0.5),
+            ..Default::default()
+        };
+        let mut config = GuildConfig::default();
+        config.nsfw_policies.insert(
+            category,
+            NsfwPolicy {
+                mode: NsfwMode::Alert,
+                thresholds: Some(strict.clone()),
+            },
+        );
+        assert_eq!(
+            config.nsfw_policy(&[thread, channel, category], false),
+            (NsfwMode::Alert, &strict)
+        );
+        config.nsfw_policies.insert(
+            channel,
+            NsfwPolicy {
+                mode: NsfwMode::Enforce,
+                thresholds: None,
+            },
+        );
+        // an explicit policy wins over discord's age restriction
+        assert_eq!(
+            config.nsfw_policy(&[thread, channel, category], true),
+            (NsfwMode::Enforce, &NsfwThresholds::default())
+        );
+        assert_eq!(config.nsfw_policy(&[other], true).0, NsfwMode::Off);
+        assert_eq!(config.nsfw_policy(&[other], false).0, NsfwMode::Enforce);
+
+        let stored: GuildConfig =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(stored, config);
+    }
+
+    #[test]
+    fn ml_range_sets_every_category() {
+        let thresholds = NsfwThresholds::with_ml_range(Some("0.4"));
+        assert_eq!(thresholds.hentai, Some(0.4));
+        assert_eq!(thresholds.porn, Some(0.4));
+        assert_eq!(thresholds.sexy, Some(0.4));
+        assert_eq!(thresholds.average, 0.9);
+        assert_eq!(
+            NsfwThresholds::with_ml_range(Some("40")),
+            NsfwThresholds::default()
+        );
+        assert_eq!(
+            NsfwThresholds::with_ml_range(None),
+            NsfwThresholds::default()
+        );
+    }
+
+    #[test]
+    fn typosquat_distance_scales_with_brand_length() {
+        let distance = TyposquatDistance::default();
+        assert_eq!(distance.allowed("twitch"), 0);
+        assert_eq!(distance.allowed("discord"), 1);
+        assert_eq!(distance.allowed("steamcommunity"), 1);
+
+        let fixed = TyposquatDistance {
+            max: 2,
+            chars_per_edit: 0,
+        };
+        assert_eq!(fixed.allowed("riot"), 2);
+        assert_eq!(fixed.allowed("steamcommunity"), 2);
+
+        let off = TyposquatDistance {
+            max: 0,
+            chars_per_edit: 3,
+        };
+        assert_eq!(off.allowed("discord"), 0);
+    }
+}
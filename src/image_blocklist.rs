@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude::{GuildId, UserId};
+use rusqlite::params;
+
+use crate::cases::CaseId;
+use crate::database::Database;
+use crate::image_hash::{decode_hash, encode_hash, hamming_distance, is_distinctive};
+
+/// Hashes of images moderators blocked in a guild, media matching one is removed on sight.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImageBlocklist {
+    hashes: Vec<u64>,
+}
+
+impl ImageBlocklist {
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// The first of `hashes` at most `max_distance` bits away from a blocked hash.
+    pub fn find(&self, hashes: &[u64], max_distance: u32) -> Option<u64> {
+        hashes
+            .iter()
+            .copied()
+            .filter(|&hash| is_distinctive(hash))
+            .find(|&hash| {
+                self.hashes
+                    .iter()
+                    .any(|&blocked| hamming_distance(hash, blocked) <= max_distance)
+            })
+    }
+}
+
+impl Database {
+    /// Blocks images by their hashes, returns how many weren't blocked already.
+    pub fn block_images(
+        &self,
+        guild_id: GuildId,
+        hashes: &[u64],
+        case_id: Option<CaseId>,
+        blocked_by: UserId,
+        now: DateTime<Utc>,
+    ) -> rusqlite::Result<usize> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "INSERT OR IGNORE INTO blocked_images (guild_id, hash, case_id, blocked_by, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        let mut added = 0;
+        for &hash in hashes {
+            added += statement.execute(params![
+                guild_id.get(),
+                encode_hash(hash),
+                case_id,
+                blocked_by.get(),
+                now
+            ])?;
+        }
+        Ok(added)
+    }
+
+    /// Unblocks the images that were blocked from a case, returns how many hashes were removed.
+    pub fn unblock_case_images(
+        &self,
+        guild_id: GuildId,
+        case_id: CaseId,
+    ) -> rusqlite::Result<usize> {
+        self.connection().execute(
+            "DELETE FROM blocked_images WHERE guild_id = ?1 AND case_id = ?2",
+            params![guild_id.get(), case_id],
+        )
+    }
+
+    pub fn image_blocklist(&self, guild_id: GuildId) -> rusqlite::Result<ImageBlocklist> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT hash FROM blocked_images WHERE guild_id = ?1")?;
+        let hashes = statement
+            .query_map([guild_id.get()], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .iter()
+            .filter_map(|hash| decode_hash(hash))
+            .collect();
+        Ok(ImageBlocklist { hashes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocked_images_match_near_duplicates() {
+        let database = Database::open_in_memory().unwrap();
+        let guild_id = GuildId::new(1);
+        let scam = 0xf0f0_f0f0_0f0f_0f0f;
+        let moderator = UserId::new(2);
+        assert!(database.image_blocklist(guild_id).unwrap().is_empty());
+
+        assert_eq!(
+            database
+                .block_images(guild_id, &[scam, u64::MAX], Some(7), moderator, Utc::now())
+                .unwrap(),
+            2
+        );
+        // blocking the same image twice doesn't add it again
+        assert_eq!(
+            database
+                .block_images(guild_id, &[scam], None, moderator, Utc::now())
+                .unwrap(),
+            0
+        );
+        let blocklist = database.image_blocklist(guild_id).unwrap();
+        assert_eq!(
+            blocklist.find(&[0x1234, scam ^ 0b111], 3),
+            Some(scam ^ 0b111)
+        );
+        assert_eq!(blocklist.find(&[scam ^ 0b1111], 3), None);
+        // flat frames never match, even if one was blocked
+        assert_eq!(blocklist.find(&[u64::MAX], 3), None);
+        assert!(database
+            .image_blocklist(GuildId::new(3))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(database.unblock_case_images(guild_id, 7).unwrap(), 2);
+        assert!(database.image_blocklist(guild_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hashes_with_the_high_bit_set_round_trip() {
+        let database = Database::open_in_memory().unwrap();
+        let guild_id = GuildId::new(1);
+        let hashes = [1 << 63, 0xf0f0_f0f0_0f0f_0f0f, u64::MAX];
+        database
+            .block_images(guild_id, &hashes, None, UserId::new(2), Utc::now())
+            .unwrap();
+        let mut blocked = database.image_blocklist(guild_id).unwrap().hashes;
+        blocked.sort_unstable();
+        assert_eq!(blocked, vec![1 << 63, 0xf0f0_f0f0_0f0f_0f0f, u64::MAX]);
+    }
+}
@@ -1,128 +1,189 @@
-use std::io::Read;
-use std::{io::Cursor, time::Instant};
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::time::{Duration, Instant};
 
-use bytes::Buf;
 use ffmpeg::frame::Video;
 use ffmpeg_next as ffmpeg;
-use ffmpeg_next::format::{input, Pixel};
+use ffmpeg_next::format::{input_with_dictionary, Pixel};
 use ffmpeg_next::media::Type;
 use ffmpeg_next::software::scaling::{Context, Flags};
+use ffmpeg_next::Dictionary;
 use image::codecs::gif::GifDecoder;
 use image::{AnimationDecoder, DynamicImage, ImageReader, RgbaImage};
 use itertools::Itertools;
-use log::info;
+use log::{info, warn};
 use nsfw::model::{Classification, Metric};
 use nsfw::{examine, Model};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use serenity::all::Message;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use serenity::all::{ChannelId, Context as SerenityContext, GuildChannel, Message};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::spawn_blocking;
 
-use crate::{Data, ImageContent};
+use crate::database::Database;
+use crate::detector::{DetectionContext, Detector, Evidence, Verdict};
+use crate::guild_config::{GuildConfig, NsfwMode, NsfwThresholds};
+use crate::image_blocklist::ImageBlocklist;
+use crate::image_hash::{dhash, distinct, HashCache};
+use crate::media_fetcher::{FetchError, MediaFetcher, MediaType};
+use crate::nsfw_model::{self, SharedModel};
+use crate::phishing_feeds::KnownDomains;
+use crate::qr_code;
+use crate::scoring::Action;
+use crate::{ImageContent, RejectionReason};
 
-pub async fn is_nsfw(file: &Message, data: &Data) -> Option<((ImageContent, f32), String)> {
-    if let Ok(ok) = std::env::var("NSFW_FILTER_ENABLED") {
-        if !ok.contains("true") {
-            return None;
+/// How many classified images and frames are remembered.
+const CACHE_CAPACITY: usize = 4096;
+/// Hashes at most this many bits apart are treated as the same image.
+const NEAR_DUPLICATE_DISTANCE: u32 = 4;
+/// Most frames of one piece of media kept on a case for blocking it.
+const MAX_BLOCKED_HASHES: usize = 32;
+
+pub struct ImageChecker {
+    pub model: SharedModel,
+    database: Arc<Database>,
+    known_domains: Arc<RwLock<KnownDomains>>,
+    fetcher: Arc<MediaFetcher>,
+    cache: Arc<Mutex<ScoreCache>>,
+}
+
+/// Scores of images that were already classified, keyed by their hash.
+struct ScoreCache {
+    /// The model the scores came from, the cache is emptied when it's swapped.
+    model: Weak<Model>,
+    scores: HashCache<Scores>,
+}
+
+impl ScoreCache {
+    fn for_model(&mut self, model: &Arc<Model>) -> &mut HashCache<Scores> {
+        if !self.model.ptr_eq(&Arc::downgrade(model)) {
+            self.model = Arc::downgrade(model);
+            self.scores.clear();
         }
-    } else {
-        return None;
+        &mut self.scores
     }
-    // info!("checking {file:?}");
-    // let image_urls = file.attachments.iter().map(|attachment| {
-    //     attachment.content_type.as_ref().map(|content| content.starts_with("image").then(|| attachment.proxy_url.clone()));
-    // });
-    let images = file
-        .embeds
-        .iter()
-        .filter_map(|e| e.thumbnail.as_ref())
-        .map(|i| i.proxy_url.as_deref().unwrap_or(i.url.as_str()))
-        .chain(
-            file.attachments
-                .iter()
-                .filter(|i| {
-                    i.content_type
-                        .as_ref()
-                        .map(|c| c.starts_with("image"))
-                        .unwrap_or_default()
-                })
-                .map(|p| p.proxy_url.as_str()),
-        );
+}
 
-    let videos = futures::future::join_all(
-        file.embeds
-            .iter()
-            .flat_map(|e| {
-                e.video
-                    .as_ref()
-                    .map(|v| v.proxy_url.as_deref().unwrap_or(v.url.as_str()))
-            })
-            .chain(
-                file.attachments
-                    .iter()
-                    .filter(|i| {
-                        i.content_type
-                            .as_ref()
-                            .map(|c| c.starts_with("video"))
-                            .unwrap_or_default()
-                    })
-                    .map(|v| v.proxy_url.as_str()),
-            )
-            .map(|video| async move { data.image_checker.is_video_nsfw(video).await }),
-    )
-    .await;
-    let gifs = futures::future::join_all(
-        file.attachments
-            .iter()
-            .filter(|a| {
-                a.content_type
-                    .as_ref()
-                    .map(|content| content.eq("image/gif"))
-                    .unwrap_or_default()
-            })
-            .map(|a| a.proxy_url.as_str())
-            .map(|a| async move { data.image_checker.is_gif_nsfw(a).await }),
-    )
-    .await;
-
-    let values = futures::future::join_all(images.map(|url| async move {
-        if url.ends_with(".gif") {
-            data.image_checker.is_gif_nsfw(&url).await
-        } else if url.ends_with(".webm") || url.ends_with(".mp4") {
-            data.image_checker.is_video_nsfw(&url).await
-        } else {
-            data.image_checker.is_image_nsfw(&url).await
+/// Score of every metric for one image or frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Scores {
+    pub drawings: f32,
+    pub hentai: f32,
+    pub neutral: f32,
+    pub porn: f32,
+    pub sexy: f32,
+}
+
+impl Scores {
+    pub const NAMES: [&'static str; 5] = ["drawings", "hentai", "neutral", "porn", "sexy"];
+
+    pub fn from_classifications(classifications: &[Classification]) -> Self {
+        let mut scores = Self::default();
+        for classification in classifications {
+            let score = classification.score;
+            match classification.metric {
+                Metric::Drawings => scores.drawings = score,
+                Metric::Hentai => scores.hentai = score,
+                Metric::Neutral => scores.neutral = score,
+                Metric::Porn => scores.porn = score,
+                Metric::Sexy => scores.sexy = score,
+            }
         }
-    }))
-    .await;
-    // let values = futures::future::join_all(file.attachments.iter().map(|attachment| async move {
-    //     if let Some(true) = attachment.content_type.as_ref().map(|content| content.starts_with("image")) {
-    //         data.image_checker.is_url_nsfw(&attachment.proxy_url).await.unwrap_or_default()
-    //     } else {
-    //         false
-    //     }
-    // })).await;
-    values
+        scores
+    }
+
+    pub fn values(&self) -> [f32; 5] {
+        [
+            self.drawings,
+            self.hentai,
+            self.neutral,
+            self.porn,
+            self.sexy,
+        ]
+    }
+
+    /// Mean of each metric over the frames, the same average the nsfw detector compares against
+    /// its threshold.
+    pub fn average(frames: &[Scores]) -> Self {
+        let count = frames.len().max(1) as f32;
+        let sum = |metric: fn(&Scores) -> f32| frames.iter().map(metric).sum::<f32>() / count;
+        Self {
+            drawings: sum(|s| s.drawings),
+            hentai: sum(|s| s.hentai),
+            neutral: sum(|s| s.neutral),
+            porn: sum(|s| s.porn),
+            sexy: sum(|s| s.sexy),
+        }
+    }
+
+    /// Categories at or above their threshold, categories without a threshold are turned off.
+    fn flagged(&self, thresholds: &NsfwThresholds) -> Vec<(ImageContent, f32)> {
+        [
+            (ImageContent::Hentai, self.hentai, thresholds.hentai),
+            (ImageContent::Porn, self.porn, thresholds.porn),
+            (ImageContent::Sexy, self.sexy, thresholds.sexy),
+        ]
         .into_iter()
-        .chain(gifs.into_iter())
-        .chain(videos.into_iter())
-        .find_map(|r| r.ok().flatten())
+        .filter_map(|(label, score, threshold)| {
+            let threshold = threshold?;
+            (score >= threshold).then(|| {
+                info!("{label:?} {score} >= {threshold}");
+                (label, score)
+            })
+        })
+        .collect()
+    }
 }
 
-pub struct ImageChecker {
-    pub model: Model,
+fn decode_image(bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+    Ok(ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?
+        .to_rgba8())
+}
+
+/// Frames of a gif, frames that fail to decode are skipped.
+fn decode_gif(bytes: &[u8]) -> anyhow::Result<Vec<RgbaImage>> {
+    let gif = GifDecoder::new(Cursor::new(bytes))?;
+    Ok(gif
+        .into_frames()
+        .filter_map(|frame| Some(frame.ok()?.into_buffer()))
+        .collect())
+}
+
+/// Scores of every metric for a still image.
+pub fn classify_image(model: &Model, bytes: &[u8]) -> anyhow::Result<Vec<Classification>> {
+    examine(model, &decode_image(bytes)?)
+        .map_err(|e| anyhow::anyhow!("Failed to classify nsfw: {e}"))
+}
+
+/// Scores of every metric for each frame of a gif, frames that fail to decode are skipped.
+pub fn classify_gif(model: &Model, bytes: &[u8]) -> anyhow::Result<Vec<Vec<Classification>>> {
+    Ok(classify_frames(model, decode_gif(bytes)?))
+}
+
+/// Scores of every metric for each frame, classified in parallel.
+pub fn classify_frames(model: &Model, frames: Vec<RgbaImage>) -> Vec<Vec<Classification>> {
+    frames
+        .into_par_iter()
+        .filter_map(|frame| examine(model, &frame).ok())
+        .collect()
 }
 
 fn average_classification(
     classifications: impl Iterator<Item = impl Iterator<Item = (ImageContent, f32)>>,
     num_frames: usize,
+    threshold: f32,
 ) -> Option<(ImageContent, f32)> {
     let keys = classifications.flatten().into_group_map();
     info!("{keys:?}");
     keys.into_iter().find_map(|(key, values)| {
         let average_value = values.iter().sum::<f32>() / num_frames as f32;
         info!("{key:?} average {average_value}");
-        if average_value > 0.9 {
+        if average_value > threshold {
             Some((key, average_value))
         } else {
             None
@@ -130,70 +191,453 @@ fn average_classification(
     })
 }
 
-impl ImageChecker {
-    async fn is_image_nsfw(
-        &self,
-        url: &str,
-    ) -> anyhow::Result<Option<((ImageContent, f32), String)>> {
-        info!("Checking {url}");
-        let bytes = reqwest::get(url).await?.bytes().await?;
-        let mut image = Vec::new();
-        let _ = bytes.reader().read_to_end(&mut image)?;
-        let image = Cursor::new(image);
-        let reader = ImageReader::new(image).with_guessed_format()?;
-        let image = reader.decode()?;
-        let buffer = image.to_rgba8();
-        let values = examine(&self.model, &buffer)
-            .map_err(|e| anyhow::anyhow!("Failed to classify nsfw: {e}"))?;
+/// Media a message was flagged for.
+struct FlaggedMedia {
+    reason: RejectionReason,
+    confidence: Option<f32>,
+    /// Shown to moderators instead of the media.
+    evidence: Option<String>,
+    /// What to do about it, `None` leaves it to the channel's nsfw mode.
+    action: Option<Action>,
+    url: String,
+    /// Hashes of the frames checked so far, so moderators can block the media.
+    hashes: Vec<u64>,
+}
+
+impl FlaggedMedia {
+    fn nsfw(content: ImageContent, confidence: f32, url: &str, hashes: Vec<u64>) -> Self {
+        Self {
+            reason: RejectionReason::ImageReason(content),
+            confidence: Some(confidence),
+            evidence: None,
+            action: None,
+            url: url.to_string(),
+            hashes,
+        }
+    }
+}
+
+/// What a message's media is checked against.
+struct MediaCheck {
+    blocklist: ImageBlocklist,
+    max_distance: u32,
+    /// `None` where the nsfw model shouldn't run.
+    thresholds: Option<NsfwThresholds>,
+    /// Config to check links in QR codes with, `None` if the phishing detector is off.
+    qr_codes: Option<GuildConfig>,
+}
+
+/// Downloaded video written out for ffmpeg to read, removed when dropped.
+struct VideoFile(PathBuf);
+
+impl VideoFile {
+    fn write(bytes: &[u8]) -> std::io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "video-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for VideoFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Unable to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+impl MediaCheck {
+    /// Media that couldn't be downloaded. Moderators hear about media too large to check where
+    /// the nsfw model runs, elsewhere it's only logged.
+    fn skipped(&self, url: &str, error: FetchError) -> anyhow::Result<Option<FlaggedMedia>> {
+        match error {
+            FetchError::TooLarge { .. } => {
+                info!("{url} {error}");
+                Ok(Some(FlaggedMedia {
+                    reason: RejectionReason::ImageReason(ImageContent::TooLarge),
+                    confidence: None,
+                    evidence: Some(format!("`{url}` {error}")),
+                    action: Some(if self.thresholds.is_some() {
+                        Action::Alert
+                    } else {
+                        Action::LogOnly
+                    }),
+                    url: url.to_string(),
+                    hashes: vec![],
+                }))
+            }
+            error => Err(error.into()),
+        }
+    }
+
+    /// Videos only go through the blocklist and the model, they aren't scanned for QR codes.
+    fn checks_videos(&self) -> bool {
+        self.thresholds.is_some() || !self.blocklist.is_empty()
+    }
+
+    fn blocked(&self, url: &str, hashes: &[u64]) -> Option<FlaggedMedia> {
+        let hash = self.blocklist.find(hashes, self.max_distance)?;
+        info!("{url} matches blocked image {hash:016x}");
+        Some(FlaggedMedia {
+            reason: RejectionReason::ImageReason(ImageContent::Blocked),
+            confidence: None,
+            evidence: None,
+            // blocked images go on sight, even where nsfw media is allowed
+            action: Some(Action::DeleteAndMute),
+            url: url.to_string(),
+            hashes: hashes.to_vec(),
+        })
+    }
+}
+
+/// The CPU bound part of checking a message's media, decoding, hashing, QR codes and the model,
+/// owned so it can run on the blocking thread pool.
+#[derive(Clone)]
+struct FrameChecker {
+    model: Arc<Model>,
+    cache: Arc<Mutex<ScoreCache>>,
+    known_domains: Arc<RwLock<KnownDomains>>,
+    check: Arc<MediaCheck>,
+}
+
+impl FrameChecker {
+    /// Scores of each frame, frames that look like something classified before reuse its scores
+    /// instead of running the model. `hashes` are the frames' [`dhash`]es, frames that fail to
+    /// classify are skipped.
+    fn classify(&self, frames: Vec<RgbaImage>, hashes: &[u64]) -> Vec<Scores> {
+        let mut scores: Vec<Option<Scores>> = {
+            let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            let cache = cache.for_model(&self.model);
+            hashes
+                .iter()
+                .map(|&hash| cache.get(hash, NEAR_DUPLICATE_DISTANCE))
+                .collect()
+        };
+        let misses: Vec<_> = frames
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| scores[*index].is_none())
+            .collect();
+        let classified: Vec<_> = misses
+            .into_par_iter()
+            .filter_map(|(index, frame)| {
+                let classifications = examine(&self.model, &frame).ok()?;
+                Some((index, Scores::from_classifications(&classifications)))
+            })
+            .collect();
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let cache = cache.for_model(&self.model);
+        for (index, classified) in classified {
+            cache.insert(hashes[index], classified);
+            scores[index] = Some(classified);
+        }
+        scores.into_iter().flatten().collect()
+    }
+
+    fn check_image(&self, url: &str, bytes: &[u8]) -> anyhow::Result<Option<FlaggedMedia>> {
+        let image = decode_image(bytes)?;
+        let hashes = vec![dhash(&image)];
+        if let Some(blocked) = self.check.blocked(url, &hashes) {
+            return Ok(Some(blocked));
+        }
+        let qr_code = self.check_qr_codes(url, &image, &hashes);
+        if qr_code.as_ref().and_then(|found| found.action) == Some(Action::DeleteAndMute) {
+            return Ok(qr_code);
+        }
+        let Some(thresholds) = &self.check.thresholds else {
+            return Ok(qr_code);
+        };
+        let values = self.classify(vec![image], &hashes);
         info!("{values:?}");
-        let value = values.into_iter().find_map(Self::check_classification);
-        Ok(value.map(|v| (v, url.to_string())))
-        // examine(&model, image)
+        let value = values
+            .first()
+            .and_then(|scores| scores.flagged(thresholds).into_iter().next());
+        Ok(value
+            .map(|(content, confidence)| FlaggedMedia::nsfw(content, confidence, url, hashes))
+            .or(qr_code))
     }
 
-    async fn is_gif_nsfw(
-        &self,
-        url: &str,
-    ) -> anyhow::Result<Option<((ImageContent, f32), String)>> {
-        let bytes = reqwest::get(url).await?.bytes().await?;
-        let model = &self.model;
-        let mut image = Vec::new();
-        let _ = bytes.reader().read_to_end(&mut image)?;
+    /// Phishing links in the image's QR codes.
+    fn check_qr_codes(&self, url: &str, image: &RgbaImage, hashes: &[u64]) -> Option<FlaggedMedia> {
+        let config = self.check.qr_codes.as_ref()?;
+        let texts = qr_code::decode(image);
+        if texts.is_empty() {
+            return None;
+        }
+        info!("{url} has QR codes {texts:?}");
+        let finding = {
+            let known = self
+                .known_domains
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            qr_code::check(&texts, config, &known)?
+        };
+        Some(FlaggedMedia {
+            reason: RejectionReason::SpamReason(finding.reason),
+            confidence: None,
+            evidence: Some(format!(
+                "{}\nImage: {url}",
+                finding.score.breakdown(&config.score_thresholds)
+            )),
+            action: Some(finding.action),
+            url: url.to_string(),
+            hashes: hashes.to_vec(),
+        })
+    }
+
+    fn check_gif(&self, url: &str, bytes: &[u8]) -> anyhow::Result<Option<FlaggedMedia>> {
         let start = Instant::now();
-        let image = Cursor::new(image);
-        let gif = GifDecoder::new(image)?;
-        let frame_data: Vec<_> = gif
-            .into_frames()
-            .filter_map(|frame| {
-                examine(model, &frame.ok()?.into_buffer())
-                    .map_err(|e| anyhow::anyhow!("{e}"))
-                    .ok()
-            })
-            .map(|classifications| {
-                classifications
-                    .into_iter()
-                    .filter_map(Self::check_classification)
-                    .collect::<Vec<_>>()
-            })
+        let frames = decode_gif(bytes)?;
+        let hashes: Vec<u64> = frames.par_iter().map(dhash).collect();
+        if let Some(blocked) = self.check.blocked(url, &hashes) {
+            return Ok(Some(blocked));
+        }
+        // a QR code in a gif is almost always there from the first frame
+        let qr_code = frames
+            .first()
+            .and_then(|frame| self.check_qr_codes(url, frame, &hashes));
+        if qr_code.as_ref().and_then(|found| found.action) == Some(Action::DeleteAndMute) {
+            return Ok(qr_code);
+        }
+        let Some(thresholds) = &self.check.thresholds else {
+            return Ok(qr_code);
+        };
+        let frame_data: Vec<_> = self
+            .classify(frames, &hashes)
+            .into_iter()
+            .map(|scores| scores.flagged(thresholds))
             .collect();
         let is_nsfw = average_classification(
             frame_data.iter().map(|i| i.iter().copied()),
             frame_data.len(),
+            thresholds.average,
         );
 
         let elapsed = Instant::now() - start;
         info!("Processed gif in : {} ms", elapsed.as_millis());
         // let is_nsfw = is_nsfw?;
-        Ok(is_nsfw.map(|c| (c, url.to_string())))
+        Ok(is_nsfw
+            .map(|(content, confidence)| FlaggedMedia::nsfw(content, confidence, url, hashes))
+            .or(qr_code))
+    }
+
+    /// Hashes of a batch of video frames, the blocked media among them and what the model flags
+    /// in each frame.
+    fn check_frames(
+        &self,
+        url: &str,
+        frames: Vec<RgbaImage>,
+    ) -> (
+        Vec<u64>,
+        Option<FlaggedMedia>,
+        Vec<Vec<(ImageContent, f32)>>,
+    ) {
+        let hashes: Vec<u64> = frames.par_iter().map(dhash).collect();
+        if let Some(blocked) = self.check.blocked(url, &hashes) {
+            return (hashes, Some(blocked), vec![]);
+        }
+        let flagged = match &self.check.thresholds {
+            Some(thresholds) => self
+                .classify(frames, &hashes)
+                .into_iter()
+                .map(|scores| scores.flagged(thresholds))
+                .collect(),
+            None => vec![],
+        };
+        (hashes, None, flagged)
+    }
+}
+
+impl ImageChecker {
+    pub fn new(
+        model: SharedModel,
+        database: Arc<Database>,
+        known_domains: Arc<RwLock<KnownDomains>>,
+        fetcher: Arc<MediaFetcher>,
+    ) -> Self {
+        Self {
+            model,
+            database,
+            known_domains,
+            fetcher,
+            cache: Arc::new(Mutex::new(ScoreCache {
+                model: Weak::new(),
+                scores: HashCache::new(CACHE_CAPACITY),
+            })),
+        }
+    }
+
+    async fn check_media(&self, file: &Message, check: MediaCheck) -> Option<FlaggedMedia> {
+        let checker = &FrameChecker {
+            // keeps using the same model if it's swapped while this message is checked
+            model: nsfw_model::current(&self.model),
+            cache: self.cache.clone(),
+            known_domains: self.known_domains.clone(),
+            check: Arc::new(check),
+        };
+        // info!("checking {file:?}");
+        // let image_urls = file.attachments.iter().map(|attachment| {
+        //     attachment.content_type.as_ref().map(|content| content.starts_with("image").then(|| attachment.proxy_url.clone()));
+        // });
+        let images = file
+            .embeds
+            .iter()
+            .filter_map(|e| e.thumbnail.as_ref())
+            .map(|i| i.proxy_url.as_deref().unwrap_or(i.url.as_str()))
+            .chain(
+                file.attachments
+                    .iter()
+                    .filter(|i| {
+                        i.content_type
+                            .as_ref()
+                            .map(|c| c.starts_with("image"))
+                            .unwrap_or_default()
+                    })
+                    .map(|p| p.proxy_url.as_str()),
+            );
+
+        let videos = futures::future::join_all(
+            file.embeds
+                .iter()
+                .flat_map(|e| {
+                    e.video
+                        .as_ref()
+                        .map(|v| v.proxy_url.as_deref().unwrap_or(v.url.as_str()))
+                })
+                .chain(
+                    file.attachments
+                        .iter()
+                        .filter(|i| {
+                            i.content_type
+                                .as_ref()
+                                .map(|c| c.starts_with("video"))
+                                .unwrap_or_default()
+                        })
+                        .map(|v| v.proxy_url.as_str()),
+                )
+                .map(|video| async move { self.check_video(checker, video).await }),
+        )
+        .await;
+
+        let values = futures::future::join_all(
+            images.map(|url| async move { self.check_download(checker, url).await }),
+        )
+        .await;
+        // let values = futures::future::join_all(file.attachments.iter().map(|attachment| async move {
+        //     if let Some(true) = attachment.content_type.as_ref().map(|content| content.starts_with("image")) {
+        //         self.is_url_nsfw(&attachment.proxy_url).await.unwrap_or_default()
+        //     } else {
+        //         false
+        //     }
+        // })).await;
+        {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            info!(
+                "NSFW cache: {} hits, {} misses, {} entries",
+                cache.scores.hits,
+                cache.scores.misses,
+                cache.scores.len()
+            );
+        }
+        let mut too_large = None;
+        for result in values.into_iter().chain(videos.into_iter()) {
+            match result {
+                // media that couldn't be checked only matters if nothing else was found
+                Ok(Some(found))
+                    if found.reason == RejectionReason::ImageReason(ImageContent::TooLarge) =>
+                {
+                    too_large.get_or_insert(found);
+                }
+                Ok(Some(found)) => return Some(found),
+                Ok(None) => {}
+                Err(e) => info!("Couldn't check media: {e:#}"),
+            }
+        }
+        too_large
     }
 
-    async fn is_video_nsfw(
+    /// Downloads an image attachment or thumbnail and checks it as whatever its bytes say it is,
+    /// whatever its name or content type claims.
+    async fn check_download(
         &self,
+        checker: &FrameChecker,
         url: &str,
-    ) -> anyhow::Result<Option<((ImageContent, f32), String)>> {
+    ) -> anyhow::Result<Option<FlaggedMedia>> {
+        info!("Checking {url}");
+        let media = match self.fetcher.fetch(url).await {
+            Ok(media) => media,
+            Err(e) => return checker.check.skipped(url, e),
+        };
+        // decoding, QR codes and the model would hold up other messages on the async workers
+        let (owned_checker, owned_url) = (checker.clone(), url.to_string());
+        match media.kind {
+            Some(MediaType::Image) => {
+                spawn_blocking(move || owned_checker.check_image(&owned_url, &media.bytes)).await?
+            }
+            Some(MediaType::Gif) => {
+                spawn_blocking(move || owned_checker.check_gif(&owned_url, &media.bytes)).await?
+            }
+            Some(MediaType::Video) if !checker.check.checks_videos() => Ok(None),
+            Some(MediaType::Video) => self.check_downloaded_video(checker, url, media.bytes).await,
+            None => {
+                info!("Skipping {url}, it isn't an image or a video");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn check_video(
+        &self,
+        checker: &FrameChecker,
+        url: &str,
+    ) -> anyhow::Result<Option<FlaggedMedia>> {
+        if !checker.check.checks_videos() {
+            return Ok(None);
+        }
+        // ffmpeg streams the video itself, so only its size can be checked up front. Servers that
+        // reject HEAD requests or don't send a size get it downloaded within the limit instead.
+        match self.fetcher.check_size(url).await {
+            Ok(()) => self.check_video_frames(checker, url, url.to_string()).await,
+            Err(e @ FetchError::TooLarge { .. }) => checker.check.skipped(url, e),
+            Err(e) => {
+                info!("Downloading {url} instead of streaming it: {e}");
+                let media = match self.fetcher.fetch(url).await {
+                    Ok(media) => media,
+                    Err(e) => return checker.check.skipped(url, e),
+                };
+                self.check_downloaded_video(checker, url, media.bytes).await
+            }
+        }
+    }
+
+    async fn check_downloaded_video(
+        &self,
+        checker: &FrameChecker,
+        url: &str,
+        bytes: Vec<u8>,
+    ) -> anyhow::Result<Option<FlaggedMedia>> {
+        let file = spawn_blocking(move || VideoFile::write(&bytes)).await??;
+        let location = file.0.to_string_lossy().into_owned();
+        self.check_video_frames(checker, url, location).await
+    }
+
+    /// Checks frames of the video at `location`, a url or a downloaded copy of `url`.
+    async fn check_video_frames(
+        &self,
+        checker: &FrameChecker,
+        url: &str,
+        location: String,
+    ) -> anyhow::Result<Option<FlaggedMedia>> {
         let mut frames = vec![];
-        let mut stream = get_video_frames_as_stream(url.to_string());
+        let mut stream = video_frames(location, Some(self.fetcher.limits.read_timeout));
         let mut results = vec![];
+        let mut hashes = vec![];
         while let Some(frame) = stream.recv().await {
             // let debug_copy = frame.clone();
             // spawn_blocking(move || {
@@ -204,25 +648,25 @@ impl ImageChecker {
             frames.push(frame.to_rgba8());
             // info!("Checking frame {f} {url}");
             if stream.len() == 0 || frames.len() > 30 {
-                let mut temp: Vec<_> = frames
-                    .into_par_iter()
-                    .flat_map(|frame| {
-                        examine(&self.model, &frame).map_err(|e| anyhow::anyhow!("{e}"))
-                    })
-                    .map(|classes| {
-                        classes
-                            .into_iter()
-                            .flat_map(Self::check_classification)
-                            .collect::<Vec<_>>()
-                    })
-                    .collect();
-                frames = vec![];
+                let (batch_checker, batch_url) = (checker.clone(), url.to_string());
+                let batch = std::mem::take(&mut frames);
+                let (batch, blocked, mut temp) =
+                    spawn_blocking(move || batch_checker.check_frames(&batch_url, batch)).await?;
+                if blocked.is_some() {
+                    return Ok(blocked);
+                }
+                hashes.extend_from_slice(&batch);
+                let Some(thresholds) = &checker.check.thresholds else {
+                    continue;
+                };
 
                 results.append(&mut temp);
-                if let Some((class, value)) =
-                    average_classification(results.iter().map(|i| i.iter().copied()), results.len())
-                {
-                    return Ok(Some(((class, value), url.to_string())));
+                if let Some((content, confidence)) = average_classification(
+                    results.iter().map(|i| i.iter().copied()),
+                    results.len(),
+                    thresholds.average,
+                ) {
+                    return Ok(Some(FlaggedMedia::nsfw(content, confidence, url, hashes)));
                 }
             }
             // f += 1;
@@ -230,28 +674,107 @@ impl ImageChecker {
         info!("Video not NSFW");
         Ok(None)
     }
+}
 
-    fn check_classification(c: Classification) -> Option<(ImageContent, f32)> {
-        if !matches!(c.metric, Metric::Drawings | Metric::Neutral) {
-            let (threshold, label) = match c.metric {
-                Metric::Hentai => (0.85, ImageContent::Hentai),
-                Metric::Porn => (0.85, ImageContent::Porn),
-                Metric::Sexy => (0.85, ImageContent::Sexy),
-                _ => unreachable!("Match expression above disallows drawings/neutral"),
-            };
-            if c.score >= threshold {
-                info!("{c:?} >= {threshold}");
-                return Some((label, c.score));
+async fn guild_channel(ctx: &SerenityContext, channel_id: ChannelId) -> Option<GuildChannel> {
+    channel_id.to_channel(ctx).await.ok()?.guild()
+}
+
+/// Channels a message's nsfw policy can come from, from the message's channel up to its
+/// category, and whether discord marks the channel as age restricted. Threads use the policy
+/// and age restriction of the channel they're in.
+async fn policy_channels(ctx: &SerenityContext, channel_id: ChannelId) -> (Vec<ChannelId>, bool) {
+    let mut channels = vec![channel_id];
+    let mut channel = guild_channel(ctx, channel_id).await;
+    let thread_parent = channel
+        .as_ref()
+        .filter(|channel| channel.thread_metadata.is_some())
+        .map(|thread| thread.parent_id);
+    if let Some(parent_id) = thread_parent {
+        channel = match parent_id {
+            Some(parent_id) => {
+                channels.push(parent_id);
+                guild_channel(ctx, parent_id).await
             }
+            None => None,
+        };
+    }
+    let age_restricted = channel.as_ref().map_or(false, |channel| channel.nsfw);
+    channels.extend(channel.and_then(|channel| channel.parent_id));
+    (channels, age_restricted)
+}
+
+#[poise::async_trait]
+impl Detector for ImageChecker {
+    /// Not toggled as a whole, the nsfw toggle only turns off the model. Blocked images and QR
+    /// codes are checked even where nsfw media is allowed.
+    fn name(&self) -> &'static str {
+        "media"
+    }
+
+    async fn detect(&self, context: &DetectionContext<'_>) -> Option<Verdict> {
+        let message = context.message;
+        if message.attachments.is_empty() && message.embeds.is_empty() {
+            return None;
         }
-        None
+        let blocklist = self
+            .database
+            .image_blocklist(context.member.guild_id)
+            .unwrap_or_else(|e| {
+                warn!("Unable to read the image blocklist {e}");
+                ImageBlocklist::default()
+            });
+        // resolved before anything is downloaded so channels that are off cost nothing
+        let (channels, age_restricted) = policy_channels(context.ctx, message.channel_id).await;
+        let (mode, thresholds) = context.config.nsfw_policy(&channels, age_restricted);
+        let thresholds =
+            (context.config.detectors.nsfw && mode != NsfwMode::Off && thresholds.any_enabled())
+                .then(|| thresholds.clone());
+        // QR codes hide phishing links, so they're checked whenever the phishing detector runs
+        let qr_codes = context
+            .config
+            .detectors
+            .phishing
+            .then(|| context.config.clone());
+        if thresholds.is_none() && blocklist.is_empty() && qr_codes.is_none() {
+            return None;
+        }
+        let check = MediaCheck {
+            blocklist,
+            max_distance: context.config.blocked_image_distance,
+            thresholds,
+            qr_codes,
+        };
+        let flagged = self.check_media(message, check).await?;
+        let action = flagged.action.unwrap_or(match mode {
+            NsfwMode::Alert => Action::Alert,
+            _ => Action::DeleteAndMute,
+        });
+        let evidence = match flagged.evidence {
+            Some(text) => Evidence::Text(text),
+            None => Evidence::Media(flagged.url),
+        };
+        Some(Verdict {
+            reason: flagged.reason,
+            confidence: flagged.confidence,
+            evidence: Some(evidence),
+            media_hashes: distinct(&flagged.hashes, NEAR_DUPLICATE_DISTANCE, MAX_BLOCKED_HASHES),
+            action,
+        })
     }
 }
 
-fn get_video_frames_as_stream(url: String) -> Receiver<DynamicImage> {
+/// Samples up to about 500 frames of a video, `location` is a url or a path to a local file.
+/// `timeout` is the longest ffmpeg waits to connect or for the next read of a url.
+pub fn video_frames(location: String, timeout: Option<Duration>) -> Receiver<DynamicImage> {
     let (sender, recv) = tokio::sync::mpsc::channel(num_cpus::get_physical());
     spawn_blocking(move || {
-        let mut ictx = input(&url)?;
+        let mut options = Dictionary::new();
+        if let Some(timeout) = timeout {
+            // in microseconds
+            options.set("rw_timeout", &timeout.as_micros().to_string());
+        }
+        let mut ictx = input_with_dictionary(&location, options)?;
         let input = ictx
             .streams()
             .best(Type::Video)
@@ -328,3 +851,28 @@ fn nearest_bigger_div_by_8(mut n: u32) -> u32 {
     n += 7;
     return n & !7;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_enabled_categories_are_flagged() {
+        let scores = Scores {
+            hentai: 0.9,
+            porn: 0.5,
+            sexy: 0.95,
+            ..Default::default()
+        };
+        let thresholds = NsfwThresholds {
+            hentai: Some(0.8),
+            porn: Some(0.8),
+            sexy: None,
+            average: 0.5,
+        };
+        assert_eq!(
+            scores.flagged(&thresholds),
+            vec![(ImageContent::Hentai, 0.9)]
+        );
+    }
+}
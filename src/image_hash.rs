@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+/// 64 bit difference hash of an image. Each bit says whether a pixel of the image shrunk to 9x8
+/// grayscale is darker than its right neighbour, so resized, recompressed or slightly edited
+/// copies end up a few bits apart.
+pub fn dhash(image: &RgbaImage) -> u64 {
+    let small = imageops::resize(&imageops::grayscale(image), 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(left < right);
+        }
+    }
+    hash
+}
+
+/// How many bits two hashes differ in.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Flat images like black frames hash to almost no bits set, or all of them, and would match every
+/// other flat image.
+pub fn is_distinctive(hash: u64) -> bool {
+    (8..=56).contains(&hash.count_ones())
+}
+
+/// How hashes are stored in the database, as fixed width hex because sqlite has no unsigned 64 bit
+/// integers.
+pub fn encode_hash(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+pub fn decode_hash(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// The distinctive hashes, leaving out ones within `max_distance` of a hash already kept, at most
+/// `limit` of them.
+pub fn distinct(hashes: &[u64], max_distance: u32, limit: usize) -> Vec<u64> {
+    let mut kept: Vec<u64> = vec![];
+    for &hash in hashes {
+        if kept.len() >= limit {
+            break;
+        }
+        if is_distinctive(hash)
+            && kept
+                .iter()
+                .all(|&other| hamming_distance(hash, other) > max_distance)
+        {
+            kept.push(hash);
+        }
+    }
+    kept
+}
+
+/// Bounded cache from image hash to a value, the least recently used entry is dropped first.
+/// Lookups also match hashes a few bits away so near duplicates hit.
+#[derive(Debug)]
+pub struct HashCache<V> {
+    capacity: usize,
+    /// Most recently used first.
+    entries: VecDeque<(u64, V)>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<V: Clone> HashCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The value of the closest hash at most `max_distance` bits away.
+    pub fn get(&mut self, hash: u64, max_distance: u32) -> Option<V> {
+        let closest = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, (cached, _))| (index, hamming_distance(hash, *cached)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by_key(|(_, distance)| *distance);
+        match closest {
+            Some((index, _)) => {
+                self.hits += 1;
+                let entry = self.entries.remove(index)?;
+                let value = entry.1.clone();
+                self.entries.push_front(entry);
+                Some(value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, hash: u64, value: V) {
+        self.entries.retain(|(cached, _)| *cached != hash);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_back();
+        }
+        if self.capacity > 0 {
+            self.entries.push_front((hash, value));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn gradient(width: u32, height: u32, brightness: u8) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let value = ((x * 200 / width + y * 50 / height) as u8).saturating_add(brightness);
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    #[test]
+    fn near_duplicates_hash_close_together() {
+        let original = dhash(&gradient(300, 200, 0));
+        assert_eq!(original, dhash(&gradient(300, 200, 0)));
+        assert!(hamming_distance(original, dhash(&gradient(150, 100, 0))) <= 4);
+        assert!(hamming_distance(original, dhash(&gradient(300, 200, 5))) <= 4);
+
+        let image = gradient(300, 200, 0);
+        let mirrored = RgbaImage::from_fn(300, 200, |x, y| *image.get_pixel(299 - x, y));
+        assert!(hamming_distance(original, dhash(&mirrored)) > 32);
+    }
+
+    #[test]
+    fn hashes_round_trip_through_hex() {
+        for hash in [0, 1, 1 << 63, 0x8000_0000_0000_0001, u64::MAX] {
+            assert_eq!(decode_hash(&encode_hash(hash)), Some(hash));
+        }
+        assert_eq!(encode_hash(1 << 63), "8000000000000000");
+        assert_eq!(decode_hash("not hex"), None);
+    }
+
+    #[test]
+    fn flat_and_repeated_frames_are_left_out() {
+        let frame = 0x00ff_00ff_00ff_00ff;
+        let hashes = [0, frame, frame ^ 1, u64::MAX, !frame, frame ^ 0xff];
+        assert_eq!(distinct(&hashes, 4, 10), vec![frame, !frame, frame ^ 0xff]);
+        assert_eq!(distinct(&hashes, 4, 2), vec![frame, !frame]);
+    }
+
+    #[test]
+    fn cache_drops_the_least_recently_used() {
+        let mut cache = HashCache::new(2);
+        cache.insert(0b0000, "a");
+        cache.insert(0b1111_0000, "b");
+        assert_eq!(cache.get(0b0000, 0), Some("a"));
+        // b is now the least recently used
+        cache.insert(0b1111_1111_0000_0000, "c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(0b1111_0000, 0), None);
+        // one bit away still hits
+        assert_eq!(cache.get(0b0001, 1), Some("a"));
+        assert_eq!(cache.get(0b0011, 1), None);
+        assert_eq!((cache.hits, cache.misses), (2, 2));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
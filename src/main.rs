@@ -1,43 +1,175 @@
+pub mod allow_list;
+pub mod cases;
+pub mod classify;
 pub mod commands;
+pub mod database;
+pub mod detector;
 pub mod error;
+pub mod eval;
+pub mod guild_config;
+pub mod image_blocklist;
 pub mod image_detection;
-
+pub mod image_hash;
+pub mod media_fetcher;
+pub mod moderation;
+pub mod nsfw_model;
+pub mod phishing;
+pub mod phishing_feeds;
+pub mod qr_code;
+pub mod reload;
+pub mod rules;
+pub mod scoring;
+
+use std::collections::HashMap;
 use std::env;
-use std::io::Cursor;
-use std::sync::RwLock;
-
-use ::serenity::all::{GatewayIntents, Member, UserId};
-use chrono::{DateTime, Utc};
-use futures::future::BoxFuture;
-use image_detection::{is_nsfw, ImageChecker};
-use lazy_static::lazy_static;
-use levenshtein::levenshtein;
-use log::{debug, error, info, warn};
-use nsfw::create_model;
-
-use poise::serenity_prelude::model::id::{ChannelId, RoleId};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ::serenity::all::{ChannelId, CreateEmbedFooter, GatewayIntents, GuildId, Interaction, Member};
+use cases::NewCase;
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use database::Database;
+use detector::{build_detectors, run_detectors, DetectionContext, Detector, Evidence, Verdict};
+use guild_config::GuildConfig;
+use log::{error, info, warn};
+use media_fetcher::{format_bytes, FetchLimits, MediaFetcher};
+use nsfw_model::{ModelSource, SharedModel};
+use phishing_feeds::KnownDomains;
+use rules::RuleSet;
+use scoring::Action;
+
 use poise::serenity_prelude::{
-    ButtonStyle, Color, CreateActionRow, CreateAllowedMentions, CreateButton, CreateEmbed,
-    CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, FullEvent, Message,
+    Color, CreateAllowedMentions, CreateEmbed, CreateInteractionResponse,
+    CreateInteractionResponseMessage, CreateMessage, FullEvent, Message,
 };
 use poise::{serenity_prelude as serenity, PrefixFrameworkOptions};
 
-use regex::Regex;
+/// How often the phishing feed directory is checked for changes.
+const FEED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the rule file is checked for changes.
+const RULES_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Discord moderation bot, runs the bot when no command is given.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measures the text detectors against a labeled corpus
+    Eval {
+        /// JSON lines file of `{"content": "...", "spam": true, "reason": "phishing"}`
+        #[arg(long)]
+        corpus: PathBuf,
+        /// Rule file to test instead of the built in rules
+        #[arg(long)]
+        rules: Option<PathBuf>,
+        /// Directory of phishing feeds to load
+        #[arg(long)]
+        feeds: Option<PathBuf>,
+    },
+    /// Prints the nsfw model's scores for local images, gifs and videos
+    Classify {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = classify::OutputFormat::Table)]
+        format: classify::OutputFormat,
+    },
+}
 
-use tokio::time::Duration;
+impl Command {
+    async fn run(self) -> anyhow::Result<()> {
+        match self {
+            Command::Eval {
+                corpus,
+                rules,
+                feeds,
+            } => {
+                let messages = eval::read_corpus(&corpus)?;
+                let rules = match rules {
+                    Some(path) => RuleSet::load(&path)?,
+                    None => RuleSet::builtin(),
+                };
+                let known = match feeds {
+                    Some(dir) => KnownDomains::load_dir(&dir)?,
+                    None => KnownDomains::default(),
+                };
+                println!(
+                    "Evaluated {} messages from {}\n",
+                    messages.len(),
+                    corpus.display()
+                );
+                print!("{}", eval::Report::run(&messages, &rules, &known));
+                Ok(())
+            }
+            Command::Classify { paths, format } => {
+                let model = ModelSource::from_env().load()?;
+                let mut files = vec![];
+                for path in &paths {
+                    files.push(classify::classify_file(&model, path).await?);
+                }
+                match format {
+                    classify::OutputFormat::Table => print!("{}", classify::table(&files)),
+                    classify::OutputFormat::Json => {
+                        println!("{}", serde_json::to_string_pretty(&files)?)
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 pub struct PotatoData {
-    image_checker: ImageChecker,
-    allow_list: RwLock<Vec<(UserId, DateTime<Utc>)>>,
+    /// Checks run against messages, in order.
+    detectors: Vec<Box<dyn Detector>>,
+    database: Arc<Database>,
+    guild_configs: RwLock<HashMap<GuildId, GuildConfig>>,
+    nsfw_model: SharedModel,
+    model_source: ModelSource,
+}
+
+impl PotatoData {
+    /// The config for a guild, see [`GuildConfig::fallback`] for guilds that were never
+    /// configured. Commands change this too, so the legacy guild keeps its `.env` settings once it
+    /// saves its first change.
+    fn guild_config(&self, guild_id: GuildId) -> GuildConfig {
+        self.guild_configs
+            .read()
+            .ok()
+            .and_then(|configs| configs.get(&guild_id).cloned())
+            .unwrap_or_else(|| GuildConfig::fallback(guild_id))
+    }
+
+    /// Persists a guild's config and makes it visible to new messages straight away.
+    fn save_guild_config(&self, guild_id: GuildId, config: GuildConfig) -> anyhow::Result<()> {
+        self.database.save_guild_config(guild_id, &config)?;
+        if let Ok(mut configs) = self.guild_configs.write() {
+            configs.insert(guild_id, config);
+        }
+        Ok(())
+    }
 }
 
 type PotatoContext<'a> = poise::Context<'a, PotatoData, Error>;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SpamReason {
     SexRelatedTerms,
     UrlDiscordMispell,
     Phishing,
+    HomoglyphImpersonation,
+    BlockedDomain,
+    KnownPhishingDomain,
+    /// A rule from the rule file without a more specific reason.
+    SpamRule,
 }
 
 impl SpamReason {
@@ -46,6 +178,10 @@ impl SpamReason {
             SpamReason::SexRelatedTerms => "Sex related terms",
             SpamReason::UrlDiscordMispell => "Misleading URL",
             SpamReason::Phishing => "Phishing with free terms",
+            SpamReason::HomoglyphImpersonation => "Lookalike characters in URL",
+            SpamReason::BlockedDomain => "Blocked domain",
+            SpamReason::KnownPhishingDomain => "Known phishing domain",
+            SpamReason::SpamRule => "Matched a spam rule",
         }
     }
 }
@@ -55,6 +191,10 @@ pub enum ImageContent {
     Hentai,
     Porn,
     Sexy,
+    /// Matched an image moderators blocked.
+    Blocked,
+    /// Went over the media fetcher's size limit, so it wasn't checked.
+    TooLarge,
 }
 
 impl ImageContent {
@@ -63,272 +203,233 @@ impl ImageContent {
             ImageContent::Hentai => "Hentai image content",
             ImageContent::Porn => "Porn image content",
             ImageContent::Sexy => "Sexy image content",
+            ImageContent::Blocked => "Blocked image",
+            ImageContent::TooLarge => "Media too large to check",
         }
     }
 }
 
-enum RejectionReason {
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RejectionReason {
     SpamReason(SpamReason),
-    ImageReason(((ImageContent, f32), String)),
+    ImageReason(ImageContent),
 }
 
-lazy_static! {
-    static ref DISCORD_GIFT_REGEX: Regex = Regex::new(r#"(https|http)://*(\S*)\.gift"#).unwrap();
-    // incredibly naive url regex that checks for the presence of the words discord or nitro
-    static ref ANY_URL_REGEX: Regex = Regex::new(r#"(http|https)://(\S*)\.\S*"#).unwrap();
-
-    static ref SUSPICIOUS_TERMS: Regex = Regex::new(r#"(?i)free|(?i)nitro"#).unwrap();
-    static ref MARKDOWN_URL: Regex = Regex::new(r"\[[^\]]*?://(?<link_domain>[^/:]+)\]\([^)]*?://(?<url_domain>[^/:]+)\)").unwrap();
+impl RejectionReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::SpamReason(spam) => spam.as_str(),
+            RejectionReason::ImageReason(image) => image.as_str(),
+        }
+    }
 }
 
 type Data = PotatoData;
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
-async fn is_allow_listed(author: &Member, data: &PotatoData) -> bool {
+async fn is_allow_listed(author: &Member, config: &GuildConfig, data: &PotatoData) -> bool {
     if author.user.bot {
         return true;
     }
 
-    let allowed_roles = [
-        RoleId::new(410339329202847744),
-        RoleId::new(443068255511248896),
-        RoleId::new(868914982652375091),
-    ];
     for role in &author.roles {
-        if allowed_roles.contains(&role) {
+        if config.trusted_roles.contains(&role) {
             return true;
         }
     }
 
-    if let Ok(reader) = data.allow_list.read() {
-        if let Some((_user, end_time)) = reader
-            .iter()
-            .find(|(user, _)| author.user.id == *user)
-            .copied()
-        {
+    match data
+        .database
+        .allow_list_entry(author.guild_id, author.user.id)
+    {
+        Ok(Some(entry)) => {
             let now = Utc::now();
-            if now < end_time {
+            if entry.is_active(now) {
                 return true;
-            } else {
-                drop(reader);
-                // make a pass at removing invalid dates
-                if let Ok(mut writer) = data.allow_list.write() {
-                    writer.retain(|(_, end_date)| now < *end_date);
-                }
-            }
-        }
-    }
-
-    false
-}
-
-/// Checks if the link looks like a phishing link. returns true if phishing link
-fn check_is_phishing_link(msg: &str) -> Option<SpamReason> {
-    // Filters all non discord.gift, .gift TLD's
-    if msg.contains("discord.gg") {
-        let lower_case = msg.to_lowercase();
-        let invalid_terms = [
-            "onlyfans", "only", "porn", "leak", "nsfw", "nude", "xxx", "girl", "sex",
-        ];
-        // shift towards only fans filtering
-        for term in invalid_terms {
-            if lower_case.contains(&term) {
-                return Some(SpamReason::SexRelatedTerms);
-            }
-        }
-    }
-    if let Some(cap) = DISCORD_GIFT_REGEX.captures(msg) {
-        // rust regex crate doesn't support negative look behind, instead check that the 2rd capture group in the regex matches discord.gift, if so then it's okay
-        if let Some(domain) = cap.get(2) {
-            // just discord means it's a valid url with the .gift appended
-            if domain.as_str() != "discord" {
-                println!("Failed gift regex url check");
-                return Some(SpamReason::Phishing);
             }
-        } else {
-            // this shouldn't ever happen, but just in case return true
-            error!("invalid match index {:?}", cap);
-        }
-    }
-    if let Some(cap) = ANY_URL_REGEX.captures(msg) {
-        println!("{:?}", cap);
-        if let Some(domain) = cap.get(2) {
-            let distance = levenshtein(domain.as_str(), "discord");
-            if distance < 4 && distance > 0 {
-                return Some(SpamReason::UrlDiscordMispell);
-            } else {
-                // we have a URL, check if there's other suspicious words
-                let terms = SUSPICIOUS_TERMS.find(msg);
-                if terms.is_some() {
-                    debug!("suspicious terms {:?}", terms);
-                    return Some(SpamReason::Phishing);
-                }
+            // make a pass at removing invalid dates
+            if let Err(e) = data.database.prune_allow_list(now) {
+                warn!("Unable to prune the allow list {e}");
             }
         }
+        Ok(None) => {}
+        Err(e) => warn!("Unable to read the allow list {e}"),
     }
 
-    if let Some(captures) = MARKDOWN_URL.captures(msg) {
-        let link_domain = captures.name("link_domain").map(|m| m.as_str());
-        let url_domain = captures.name("url_domain").map(|m| m.as_str());
-        if link_domain != url_domain {
-            println!("Failed markdown url check {link_domain:?} {url_domain:?}");
-            return Some(SpamReason::Phishing);
-        }
-    }
-
-    None
+    false
 }
 
 async fn check_message(
     ctx: &serenity::Context,
-    _event: &FullEvent,
+    event: &FullEvent,
     data: &Data,
     msg: &Message,
 ) -> Result<(), Error> {
-    if msg.guild_id.is_none() {
+    let Some(guild_id) = msg.guild_id else {
         return Ok(());
-    }
+    };
+    let config = data.guild_config(guild_id);
     let Ok(member) = msg.member(ctx).await else {
         warn!("Unable to find a member for message {msg:?}");
         return Ok(());
     };
-    if is_allow_listed(&member, data).await {
+    if is_allow_listed(&member, &config, data).await {
         return Ok(());
     }
-    if let Some(reject) = check_is_phishing_link(&msg.content)
-        .map(|text| RejectionReason::SpamReason(text))
-        .or(is_nsfw(msg, data)
-            .await
-            .map(|image| RejectionReason::ImageReason(image)))
-    {
-        msg.delete(ctx).await?;
-        let mod_channel = ChannelId::new(dotenv::var("MOD_CHANNEL")?.parse()?);
-        let mod_tatoe_role = dotenv::var("MOD_ROLE")?.parse()?;
-        let muted_role = RoleId::new(dotenv::var("MUTED_ROLE")?.parse()?);
-        info!("adding mute role");
-        member.add_role(ctx, muted_role).await?;
-        let reason = match &reject {
-            RejectionReason::SpamReason(spam) => spam.as_str().to_string(),
-            RejectionReason::ImageReason(((image, certainty), _)) => {
-                format!("{} - {:.0}%", image.as_str(), *certainty * 100.0)
+    let first_message = data
+        .database
+        .record_author(guild_id, member.user.id, Utc::now())
+        .unwrap_or_else(|e| {
+            warn!("Unable to record message author {e}");
+            false
+        });
+    let context = DetectionContext {
+        ctx,
+        message: msg,
+        member: &member,
+        config: &config,
+        first_message,
+    };
+    if let Some(verdict) = run_detectors(&data.detectors, &context).await {
+        if verdict.action == Action::LogOnly {
+            info!(
+                "Logging message {} from {}: {} {:?}",
+                msg.id,
+                msg.author.id,
+                verdict.reason.as_str(),
+                verdict.evidence
+            );
+            return Ok(());
+        }
+        let Some(mod_channel) = config.mod_channel else {
+            warn!(
+                "Guild {guild_id} has no mod channel configured, ignoring message {}",
+                msg.id
+            );
+            return Ok(());
+        };
+        if verdict.action == Action::Alert {
+            // embeds loading fire updates without new content, don't alert about the same text twice
+            if let FullEvent::MessageUpdate { event: update, .. } = event {
+                if update.content.is_none() {
+                    return Ok(());
+                }
             }
+            return send_alert(ctx, &config, mod_channel, msg, &verdict).await;
+        }
+        msg.delete(ctx).await?;
+        let punishment = moderation::punish(ctx, &config, &member).await?;
+        let reason = verdict.reason.as_str();
+        let confidence = verdict.confidence;
+        let (media_url, evidence) = match &verdict.evidence {
+            Some(Evidence::Media(url)) => (Some(url.as_str()), None),
+            Some(Evidence::Text(text)) => (None, Some(text.as_str())),
+            None => (None, None),
         };
-        let cleanup: BoxFuture<()> = match reject {
-            RejectionReason::SpamReason(_) => Box::pin(async move {}),
-            RejectionReason::ImageReason((_, url)) => {
-                let msg = mod_channel
+        let case_id = data.database.create_case(&NewCase {
+            guild_id: member.guild_id,
+            channel_id: msg.channel_id,
+            offender_id: member.user.id,
+            content: &msg.content,
+            reason,
+            confidence,
+            media_url,
+            evidence,
+            media_hashes: &verdict.media_hashes,
+            punishment,
+        })?;
+        let reason = match confidence {
+            Some(certainty) => format!("{} - {:.0}%", reason, certainty * 100.0),
+            None => reason.to_string(),
+        };
+        let evidence_message = match media_url {
+            Some(url) => Some(
+                mod_channel
                     .send_message(ctx, CreateMessage::new().content(url))
-                    .await?;
-                Box::pin(async move {
-                    let _ = msg.delete(ctx).await;
-                })
-            }
+                    .await?
+                    .id,
+            ),
+            None => None,
         };
 
-        let e = CreateEmbed::new().color(Color::RED)
+        let mut e = CreateEmbed::new().color(Color::RED)
         .title(reason)
         .description(format!(
-            "<@{}> sent a suspicious message `{}`\nPlease manually inspect. If it is bad, ban the user.",
+            "<@{}> sent a suspicious message\n{}\nPlease manually inspect. If it is bad, ban the user.",
             msg.author.id,
-            msg.content_safe(ctx)
-        ));
-        let c = vec![CreateActionRow::Buttons(vec![
-            CreateButton::new("unmute")
-                .label("Unmute")
-                .emoji('😇')
-                .style(ButtonStyle::Success),
-            CreateButton::new("tempallowlist")
-                .label("1 day allowlist")
-                .emoji('🟢'),
-            CreateButton::new("ban")
-                .label("Ban")
-                .emoji('🔨')
-                .style(ButtonStyle::Danger),
-        ])];
-
-        let msg = CreateMessage::new()
-            .content(format!("<@&{}>", mod_tatoe_role))
+            cases::code_block(&msg.content_safe(ctx), cases::EMBED_FIELD_LIMIT)
+        ))
+        .footer(CreateEmbedFooter::new(format!("Case #{}", case_id)));
+        if let Some(evidence) = evidence {
+            e = e.field(
+                "Evidence",
+                cases::truncate(evidence, cases::EMBED_FIELD_LIMIT),
+                false,
+            );
+        }
+
+        let mut msg = CreateMessage::new()
             .embed(e)
-            .allowed_mentions(CreateAllowedMentions::new().roles([RoleId::new(mod_tatoe_role)]))
-            .components(c);
+            .components(moderation::review_buttons(
+                case_id,
+                !verdict.media_hashes.is_empty(),
+            ));
+        if let Some(mod_tatoe_role) = config.mod_role {
+            msg = msg
+                .content(format!("<@&{}>", mod_tatoe_role))
+                .allowed_mentions(CreateAllowedMentions::new().roles([mod_tatoe_role]));
+        }
         info!("SENDING MOD MESSAGE");
         let mod_message = mod_channel.send_message(ctx, msg).await?;
-        // Now see what the user clicked.
-        if let Some(component) = mod_message
-            .await_component_interaction(ctx)
-            .timeout(Duration::from_secs(60 * 60 * 24))
-            .await
-        {
-            let user = &component.user;
-            let result = if component.data.custom_id == "ban" {
-                member
-                    .ban_with_reason(ctx, 3, "Sending phishing links")
-                    .await?;
-                "banned"
-            } else if component.data.custom_id == "unmute" {
-                info!(
-                    "unmuted user {} after moderator {} reviewed case",
-                    member, user
-                );
-                member.remove_role(ctx, muted_role).await?;
-                // this can definitely fail, but do our best
-                let _ = member
-                    .user
-                    .direct_message(
-                        ctx,
-                        CreateMessage::new()
-                            .content("You have been unmuted! Apologies for any confusion"),
-                    )
-                    .await;
-                "unmuted"
-            } else if component.data.custom_id == "tempallowlist" {
-                if let Ok(mut write) = data.allow_list.write() {
-                    write.push((user.id, Utc::now() + chrono::Duration::days(1)));
-                }
-                member.remove_role(ctx, muted_role).await?;
-                let _ = member
-                    .user
-                    .direct_message(
-                        ctx,
-                        CreateMessage::new().content(
-                            "You have been unmuted! You may try and resend your message now.",
-                        ),
-                    )
-                    .await;
-                "allowlisted"
-            } else {
-                error!("Invalid response type sent");
-                let msg = CreateInteractionResponse::UpdateMessage(
-                    CreateInteractionResponseMessage::new().content("Invalid response sent"),
-                );
-                component.create_response(ctx, msg).await?;
-                return Ok(());
-            };
+        // the buttons are served by the global interaction handler, persist what it needs so a
+        // restart doesn't leave the user muted forever
+        let expires_at = Utc::now() + moderation::review_timeout();
+        data.database.set_case_review(
+            case_id,
+            mod_channel,
+            mod_message.id,
+            evidence_message,
+            expires_at,
+        )?;
+        moderation::schedule_expiry(ctx.clone(), data.database.clone(), case_id, expires_at);
+    }
+    Ok(())
+}
 
-            let text = format!("{} {} {}", user, result, member);
-            let embed = CreateEmbed::default()
-                .title("Moderation Log")
-                .description(text)
-                .color(Color::DARK_GREEN);
-            component
-                .create_response(
-                    ctx,
-                    CreateInteractionResponse::UpdateMessage(
-                        CreateInteractionResponseMessage::new()
-                            .components(vec![])
-                            .content("Problem solved")
-                            .embed(embed),
-                    ),
-                )
-                .await?;
-            cleanup.await;
-        } else {
-            info!("Timed out, and unmuting the user");
-            mod_message.reply(ctx, "Timed out, unmuting user?").await?;
-            member.remove_role(ctx, muted_role).await?;
-            cleanup.await;
-        }
+/// Lets moderators know about a message that looks suspicious but not enough to remove it.
+async fn send_alert(
+    ctx: &serenity::Context,
+    config: &GuildConfig,
+    mod_channel: ChannelId,
+    msg: &Message,
+    verdict: &Verdict,
+) -> Result<(), Error> {
+    let title = match verdict.confidence {
+        Some(confidence) => format!("{} - {:.0}%", verdict.reason.as_str(), confidence * 100.0),
+        None => verdict.reason.as_str().to_string(),
+    };
+    let mut e = CreateEmbed::new()
+        .color(Color::ORANGE)
+        .title(title)
+        .description(format!(
+            "<@{}> sent a suspicious message {}\nIt was left up, please take a look.",
+            msg.author.id,
+            msg.link()
+        ));
+    if let Some(Evidence::Text(evidence)) = &verdict.evidence {
+        e = e.field(
+            "Evidence",
+            cases::truncate(evidence, cases::EMBED_FIELD_LIMIT),
+            false,
+        );
     }
+    let mut alert = CreateMessage::new().embed(e);
+    if let Some(mod_role) = config.mod_role {
+        alert = alert
+            .content(format!("<@&{}>", mod_role))
+            .allowed_mentions(CreateAllowedMentions::new().roles([mod_role]));
+    }
+    mod_channel.send_message(ctx, alert).await?;
     Ok(())
 }
 
@@ -339,6 +440,25 @@ async fn check_message(
 //     Ok(())
 // }
 
+/// Lets the guild's moderators know something went wrong while handling one of its events.
+async fn report_error(
+    ctx: &serenity::Context,
+    data: &Data,
+    guild_id: Option<GuildId>,
+    e: &Error,
+) -> Result<(), Error> {
+    if let Some(mod_channel) = guild_id.and_then(|guild_id| data.guild_config(guild_id).mod_channel)
+    {
+        mod_channel
+            .send_message(
+                ctx,
+                CreateMessage::new().content(format!("Something went bad! {:?}", e)),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
 async fn listener(ctx: &serenity::Context, event: &FullEvent, data: &Data) -> Result<(), Error> {
     // if matches!(
     //     event,
@@ -353,16 +473,24 @@ async fn listener(ctx: &serenity::Context, event: &FullEvent, data: &Data) -> Re
     } = event
     {
         if let Err(e) = check_message(ctx, event, data, new_message).await {
-            let mod_channel = ChannelId::new(dotenv::var("MOD_CHANNEL")?.parse()?);
-            mod_channel
-                .send_message(
-                    ctx,
-                    CreateMessage::new().content(format!("Something went bad! {:?}", e)),
-                )
-                .await?;
+            report_error(ctx, data, new_message.guild_id, &e).await?;
             error!("Encountered error sending warning {:?}", e);
         }
     };
+    if let FullEvent::InteractionCreate {
+        interaction: Interaction::Component(component),
+    } = event
+    {
+        if let Err(e) = moderation::handle_component(ctx, data, component).await {
+            error!("Encountered error handling moderation button {:?}", e);
+            let msg = CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(format!("Something went bad! {:?}", e))
+                    .ephemeral(true),
+            );
+            let _ = component.create_response(ctx, msg).await;
+        }
+    }
     if let FullEvent::MessageUpdate {
         new: None,
         event: update,
@@ -373,13 +501,7 @@ async fn listener(ctx: &serenity::Context, event: &FullEvent, data: &Data) -> Re
             // sometimes the http message doesn't actually return a guild >:(
             msg.guild_id = update.guild_id;
             if let Err(e) = check_message(ctx, event, data, &msg).await {
-                let mod_channel = ChannelId::new(dotenv::var("MOD_CHANNEL")?.parse()?);
-                mod_channel
-                    .send_message(
-                        ctx,
-                        CreateMessage::new().content(format!("Something went bad! {:?}", e)),
-                    )
-                    .await?;
+                report_error(ctx, data, msg.guild_id, &e).await?;
                 error!("Encountered error banning user {:?}", e);
             }
         }
@@ -403,32 +525,145 @@ async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
 }
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     pretty_env_logger::init();
-    ffmpeg_next::init().expect("FFMPEG to be installed");
+    if let Some(command) = Cli::parse().command {
+        return match command.run().await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{e:#}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let Err(e) = ffmpeg_next::init() {
+        error!("Failed to initialize ffmpeg: {e}");
+        return ExitCode::FAILURE;
+    }
     let token = dotenv::var("DISCORD_BOT_TOKEN").unwrap();
     let intents = serenity::GatewayIntents::non_privileged()
         .union(GatewayIntents::GUILD_MESSAGES)
         .union(GatewayIntents::MESSAGE_CONTENT);
 
-    let bytes = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/model.onnx"));
-    let bytes = Cursor::new(bytes);
-    let model = create_model(bytes).expect("ML Model to load");
-    info!("Initialized machine learning");
+    let model_source = ModelSource::from_env();
+    let model = match model_source.load() {
+        Ok(model) => Arc::new(RwLock::new(Arc::new(model))),
+        Err(e) => {
+            error!("Failed to load the nsfw model: {e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    info!("Initialized machine learning from {model_source}");
+    let detector_order = dotenv::var("DETECTORS").unwrap_or_else(|_| "phishing,nsfw".to_string());
+    let known_domains = Arc::new(RwLock::new(KnownDomains::default()));
+    if let Some(dir) = dotenv::var("PHISHING_FEEDS_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+    {
+        let dir = PathBuf::from(dir);
+        let load = {
+            let dir = dir.clone();
+            let known_domains = known_domains.clone();
+            move || match KnownDomains::load_dir(&dir) {
+                Ok(loaded) => {
+                    if let Ok(mut known) = known_domains.write() {
+                        *known = loaded;
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to load phishing feeds from {}: {e:#}",
+                    dir.display()
+                ),
+            }
+        };
+        load();
+        reload::watch(dir, FEED_POLL_INTERVAL, load);
+    }
+    let rules = match dotenv::var("RULES_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+    {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            // a broken rule file at startup is a mistake to fix, not something to run without
+            let rules = Arc::new(RwLock::new(
+                RuleSet::load(&path).unwrap_or_else(|e| panic!("Invalid rule file: {e:#}")),
+            ));
+            info!("Loaded spam rules from {}", path.display());
+            let reload = {
+                let path = path.clone();
+                let rules = rules.clone();
+                move || match RuleSet::load(&path) {
+                    Ok(loaded) => {
+                        if let Ok(mut rules) = rules.write() {
+                            *rules = loaded;
+                        }
+                        info!("Reloaded spam rules from {}", path.display());
+                    }
+                    Err(e) => error!("Keeping the previous spam rules: {e:#}"),
+                }
+            };
+            reload::watch(path, RULES_POLL_INTERVAL, reload);
+            rules
+        }
+        None => Arc::new(RwLock::new(RuleSet::builtin())),
+    };
+    let limits = FetchLimits::from_env();
+    info!(
+        "Downloading media of up to {}",
+        format_bytes(limits.max_bytes)
+    );
+    let fetcher = Arc::new(MediaFetcher::new(limits).expect("Media fetcher to build"));
+    let database_path = dotenv::var("DATABASE_PATH").unwrap_or_else(|_| "potatobot.db".to_string());
+    let database = Arc::new(Database::open(&database_path).expect("Database to open"));
+    info!("Opened case database at {database_path}");
+    let detectors = build_detectors(
+        &detector_order,
+        model.clone(),
+        database.clone(),
+        known_domains,
+        fetcher,
+        rules,
+    );
+    info!(
+        "Running detectors {:?}",
+        detectors.iter().map(|d| d.name()).collect::<Vec<_>>()
+    );
+    let guild_configs = database.guild_configs().expect("Guild configs to load");
+    info!("Loaded config for {} guilds", guild_configs.len());
+    let ignored = GuildConfig::ignored_env();
+    if !ignored.is_empty() {
+        error!(
+            "{} are set but GUILD_ID isn't, set it to the server they belong to or run /config there",
+            ignored.join(", ")
+        );
+    }
 
     let framework = poise::Framework::builder()
         .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                moderation::resume_pending_cases(ctx, &database);
                 Ok(PotatoData {
-                    image_checker: ImageChecker { model },
-                    allow_list: RwLock::new(vec![]),
+                    detectors,
+                    database,
+                    guild_configs: RwLock::new(guild_configs),
+                    nsfw_model: model,
+                    model_source,
                 })
             })
         })
         .options(poise::FrameworkOptions {
             event_handler: |ctx, event, _framework, data| Box::pin(listener(ctx, event, data)),
-            commands: vec![commands::purge()],
+            commands: vec![
+                commands::purge(),
+                commands::case(),
+                commands::reload_model(),
+                commands::unblock_image(),
+                commands::allow_list::allowlist(),
+                commands::config::config(),
+                commands::domains::domains(),
+            ],
             prefix_options: PrefixFrameworkOptions {
                 prefix: Some("~".to_string()),
                 ..Default::default()
@@ -441,133 +676,5 @@ async fn main() {
         .framework(framework)
         .await;
     client.unwrap().start().await.unwrap();
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn phishing_test() {
-        // false because not a valid URL, scammers seem to be sending URLs to make it seem official.
-        assert_eq!(
-            check_is_phishing_link(
-                "https:// asdiofjiouawejf.dgidfsdfoiwejrowet.gift aowiejroaiwerj"
-            ),
-            None
-        );
-        // assert_eq!(
-        //     check_is_phishing_link("https://phishinglink-discord.gift/dfoiwejroiwejr"),
-        //     Some(SpamReason::UrlDiscordMispell)
-        // );
-        assert_eq!(
-            check_is_phishing_link("http://discord.gift/notphishing"),
-            None
-        );
-        assert_eq!(
-            check_is_phishing_link("hey pearl i'd like you to sign my autograph for a gift for a friend on discord.com"),
-            None
-        );
-        // assert_eq!(
-        //     check_is_phishing_link("hey check out my spotify free https://spotify.com"),
-        //     None
-        // );
-        // real example, slightly modified
-        assert_eq!(
-            check_is_phishing_link(
-                "@everyone
-                    :video_game: • Get Discord Nitro for Free from Steam Store
-                    Free 3 months Discord Nitro
-                    :clock630: • The offer is valid until at 6:00PM on November 30, 2021.
-                    Personalize your profile, screen share in HD, upgrade your emojis, and more.
-                    :gem: • Click to get Nitro: https://discorda.org/welcome"
-            ),
-            Some(SpamReason::UrlDiscordMispell)
-        );
-
-        // example taken from real phishing attempt and slightly modified
-        assert_eq!(check_is_phishing_link("@​everyone 🔥Airdrop Discord FREE NITRO from Steam — https://discorcla-app.com/redeem/nitro"), Some(SpamReason::Phishing));
-
-        // Valid discord url
-        assert_eq!(
-            check_is_phishing_link("hello https://discord.com/test-url-blah i am here"),
-            None
-        );
-
-        assert_eq!(
-            check_is_phishing_link("discord.gg/girls hot girls cool cool cool"),
-            Some(SpamReason::SexRelatedTerms)
-        )
-    }
-
-    #[test]
-    fn test_phishing_link_detection() {
-        // Phishing tests
-        assert_eq!(
-            check_is_phishing_link("[Click here](https://phishing.example.com)"),
-            None
-        );
-        assert_eq!(
-            check_is_phishing_link("[http://phishing.example.com](https://not-the-same.com)"),
-            Some(SpamReason::Phishing)
-        );
-        assert_eq!(
-            check_is_phishing_link("[http://legit.example.com](http://phishing.example.com)"),
-            Some(SpamReason::Phishing)
-        );
-        assert_eq!(
-            check_is_phishing_link("[http://evil.com](https://good.com)"),
-            Some(SpamReason::Phishing)
-        );
-
-        // Discord misspelling tests
-        assert_eq!(
-            check_is_phishing_link("[Discord](https://disc0rd.com)"),
-            Some(SpamReason::UrlDiscordMispell)
-        );
-        assert_eq!(
-            check_is_phishing_link("[Join us](https://discrod.com/server)"),
-            Some(SpamReason::UrlDiscordMispell)
-        );
-
-        // Negative tests (not spam)
-        assert_eq!(
-            check_is_phishing_link("[http://example.com](https://example.com)"),
-            None
-        );
-        assert_eq!(
-            check_is_phishing_link("[My Link](https://www.example.com)"),
-            None
-        );
-        assert_eq!(
-            check_is_phishing_link("[Another Link](https://another.example.com)"),
-            None
-        );
-        assert_eq!(check_is_phishing_link("No link here"), None);
-        assert_eq!(
-            check_is_phishing_link("[Image](![alt text](image.jpg))"),
-            None
-        );
-        assert_eq!(check_is_phishing_link("[relative path](/path)"), None);
-        assert_eq!(
-            check_is_phishing_link(
-                "<img src=\"https://legit.example.com/image.jpg\" alt=\"Alt Text\">"
-            ),
-            None
-        );
-
-        // Complex URL tests (handle these carefully)
-        assert_eq!(
-            check_is_phishing_link("[http://example.com/path1/path2](https://example.com/path3)"),
-            None
-        ); // Different paths, same domain (not always phishing)
-        assert_eq!(
-            check_is_phishing_link("[http://example.com](https://example.com/path?param=value)"),
-            None
-        );
-        assert_eq!(
-            check_is_phishing_link("[http://example.com](https://example.com/#fragment)"),
-            None
-        );
-    }
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::time::Duration;
+
+use log::warn;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+
+/// Kind of media, told apart by the file's first bytes rather than its name or content type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Image,
+    Gif,
+    Video,
+}
+
+impl MediaType {
+    /// Sniffs the magic bytes at the start of a file, `None` for anything that isn't media.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        let starts = |magic: &[u8]| bytes.starts_with(magic);
+        if starts(b"GIF87a") || starts(b"GIF89a") {
+            Some(MediaType::Gif)
+        } else if starts(b"\x89PNG\r\n\x1a\n")
+            || starts(b"\xff\xd8\xff")
+            || (starts(b"RIFF") && bytes.get(8..12) == Some(b"WEBP"))
+        {
+            Some(MediaType::Image)
+        } else if bytes.get(4..8) == Some(b"ftyp") || starts(b"\x1a\x45\xdf\xa3") {
+            // mp4 and mov start with an ftyp box, webm and mkv with an EBML header
+            Some(MediaType::Video)
+        } else {
+            None
+        }
+    }
+}
+
+/// Downloaded media.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Media {
+    pub kind: Option<MediaType>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The media is bigger than the limit, it was never fully downloaded.
+    TooLarge {
+        limit: u64,
+    },
+    /// The server didn't say how big media is, so it can't be streamed within the limit.
+    UnknownSize,
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::TooLarge { limit } => {
+                write!(
+                    f,
+                    "skipped: too large, over the {} limit",
+                    format_bytes(*limit)
+                )
+            }
+            FetchError::UnknownSize => write!(f, "the server didn't send its size"),
+            FetchError::Request(e) if e.is_timeout() => write!(f, "timed out: {e}"),
+            FetchError::Request(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Request(e)
+    }
+}
+
+/// Sizes as moderators read them, e.g. `25 MB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const MB: u64 = 1024 * 1024;
+    if bytes >= MB {
+        format!("{} MB", bytes / MB)
+    } else {
+        format!("{} KB", bytes.div_ceil(1024))
+    }
+}
+
+/// How much media may be downloaded and how long it may take.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchLimits {
+    pub max_bytes: u64,
+    pub connect_timeout: Duration,
+    /// Longest wait for the next chunk of data, slow servers can't stall a check forever.
+    pub read_timeout: Duration,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 25 * 1024 * 1024,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+fn env_number(key: &str) -> Option<u64> {
+    let value = dotenv::var(key).ok()?;
+    match value.trim().parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            warn!("Ignoring {key}={value}, expected a whole number");
+            None
+        }
+    }
+}
+
+impl FetchLimits {
+    /// `MEDIA_MAX_BYTES`, `MEDIA_CONNECT_TIMEOUT_SECS` and `MEDIA_READ_TIMEOUT_SECS`, unset or
+    /// invalid values keep the default.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_bytes: env_number("MEDIA_MAX_BYTES").unwrap_or(default.max_bytes),
+            connect_timeout: env_number("MEDIA_CONNECT_TIMEOUT_SECS")
+                .map_or(default.connect_timeout, Duration::from_secs),
+            read_timeout: env_number("MEDIA_READ_TIMEOUT_SECS")
+                .map_or(default.read_timeout, Duration::from_secs),
+        }
+    }
+}
+
+/// Downloads media for the detectors through one reused client, within [`FetchLimits`].
+pub struct MediaFetcher {
+    client: Client,
+    pub limits: FetchLimits,
+}
+
+impl MediaFetcher {
+    pub fn new(limits: FetchLimits) -> reqwest::Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(limits.connect_timeout)
+            .read_timeout(limits.read_timeout)
+            .build()?;
+        Ok(Self { client, limits })
+    }
+
+    /// Downloads the media in chunks and gives up as soon as it's over the size limit.
+    pub async fn fetch(&self, url: &str) -> Result<Media, FetchError> {
+        let limit = self.limits.max_bytes;
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+        let expected = response.content_length().unwrap_or_default();
+        if expected > limit {
+            return Err(FetchError::TooLarge { limit });
+        }
+        let mut bytes = Vec::with_capacity(expected as usize);
+        while let Some(chunk) = response.chunk().await? {
+            if (bytes.len() + chunk.len()) as u64 > limit {
+                return Err(FetchError::TooLarge { limit });
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(Media {
+            kind: MediaType::sniff(&bytes),
+            bytes,
+        })
+    }
+
+    /// Checks the size of media that's streamed rather than downloaded, like videos decoded by
+    /// ffmpeg. Media of unknown size fails the check, the limit couldn't be enforced otherwise.
+    pub async fn check_size(&self, url: &str) -> Result<(), FetchError> {
+        let limit = self.limits.max_bytes;
+        let response = self.client.head(url).send().await?.error_for_status()?;
+        // the response to a HEAD request has no body, so its own content length is always 0
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok());
+        match size {
+            Some(size) if size > limit => Err(FetchError::TooLarge { limit }),
+            Some(_) => Ok(()),
+            None => Err(FetchError::UnknownSize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        assert_eq!(MediaType::sniff(b"GIF89a\x01\x00"), Some(MediaType::Gif));
+        assert_eq!(
+            MediaType::sniff(b"\x89PNG\r\n\x1a\n\x00\x00"),
+            Some(MediaType::Image)
+        );
+        assert_eq!(
+            MediaType::sniff(b"\xff\xd8\xff\xe0\x00\x10JFIF"),
+            Some(MediaType::Image)
+        );
+        assert_eq!(
+            MediaType::sniff(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some(MediaType::Image)
+        );
+        assert_eq!(
+            MediaType::sniff(b"\x00\x00\x00\x20ftypisom"),
+            Some(MediaType::Video)
+        );
+        assert_eq!(
+            MediaType::sniff(b"\x1a\x45\xdf\xa3\x9f\x42"),
+            Some(MediaType::Video)
+        );
+        // a .gif that's really a page of html
+        assert_eq!(MediaType::sniff(b"<!DOCTYPE html>"), None);
+        assert_eq!(MediaType::sniff(b"RIFF\x24\x00\x00\x00WAVE"), None);
+        assert_eq!(MediaType::sniff(b""), None);
+    }
+
+    #[test]
+    fn too_large_is_reported_clearly() {
+        let error = FetchError::TooLarge {
+            limit: 25 * 1024 * 1024,
+        };
+        assert_eq!(
+            error.to_string(),
+            "skipped: too large, over the 25 MB limit"
+        );
+        assert_eq!(format_bytes(1500), "2 KB");
+    }
+}
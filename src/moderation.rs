@@ -0,0 +1,607 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info, warn};
+use poise::serenity_prelude::{
+    self as serenity, ButtonStyle, Color, ComponentInteraction, CreateActionRow, CreateButton,
+    CreateEmbed, CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage,
+    CreateMessage, EditMember, Member, UserId,
+};
+
+use crate::allow_list::AllowListEntry;
+use crate::cases::{AppliedPunishment, Case, CaseDecision, CaseId};
+use crate::database::Database;
+use crate::guild_config::{GuildConfig, PunishmentMode, MAX_TIMEOUT_MINUTES};
+use crate::{Data, Error};
+
+/// How long moderators have to review a case before the offender is unmuted automatically.
+pub fn review_timeout() -> TimeDelta {
+    TimeDelta::days(1)
+}
+
+/// The buttons attached to a case's mod channel embed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CaseAction {
+    Unmute,
+    TempAllowlist,
+    Ban,
+}
+
+impl CaseAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CaseAction::Unmute => "unmute",
+            CaseAction::TempAllowlist => "tempallowlist",
+            CaseAction::Ban => "ban",
+        }
+    }
+
+    /// Button ids look like `case:<id>:<action>` so that any instance of the bot can serve them.
+    pub fn custom_id(&self, case_id: CaseId) -> String {
+        format!("case:{}:{}", case_id, self.as_str())
+    }
+
+    pub fn parse_custom_id(custom_id: &str) -> Option<(CaseId, Self)> {
+        let mut parts = custom_id.split(':');
+        if parts.next()? != "case" {
+            return None;
+        }
+        let case_id = parts.next()?.parse().ok()?;
+        let action = match parts.next()? {
+            "unmute" => CaseAction::Unmute,
+            "tempallowlist" => CaseAction::TempAllowlist,
+            "ban" => CaseAction::Ban,
+            _ => return None,
+        };
+        parts.next().is_none().then_some((case_id, action))
+    }
+}
+
+/// Id of the button that blocks a case's media, it doesn't decide the case so it isn't a
+/// [`CaseAction`].
+pub fn block_image_custom_id(case_id: CaseId) -> String {
+    format!("blockimage:{}", case_id)
+}
+
+pub fn parse_block_image_custom_id(custom_id: &str) -> Option<CaseId> {
+    custom_id.strip_prefix("blockimage:")?.parse().ok()
+}
+
+/// Buttons for a case, cases with media that can be blocked also get a block button.
+pub fn review_buttons(case_id: CaseId, blockable_media: bool) -> Vec<CreateActionRow> {
+    let mut buttons = vec![
+        CreateButton::new(CaseAction::Unmute.custom_id(case_id))
+            .label("Unmute")
+            .emoji('😇')
+            .style(ButtonStyle::Success),
+        CreateButton::new(CaseAction::TempAllowlist.custom_id(case_id))
+            .label("1 day allowlist")
+            .emoji('🟢'),
+        CreateButton::new(CaseAction::Ban.custom_id(case_id))
+            .label("Ban")
+            .emoji('🔨')
+            .style(ButtonStyle::Danger),
+    ];
+    if blockable_media {
+        buttons.push(
+            CreateButton::new(block_image_custom_id(case_id))
+                .label("Block this image")
+                .emoji('🚫')
+                .style(ButtonStyle::Secondary),
+        );
+    }
+    vec![CreateActionRow::Buttons(buttons)]
+}
+
+/// Silences an offender while their case is reviewed, using the guild's punishment mode.
+pub async fn punish(
+    ctx: &serenity::Context,
+    config: &GuildConfig,
+    member: &Member,
+) -> Result<AppliedPunishment, Error> {
+    match config.punishment {
+        PunishmentMode::MutedRole => {
+            let muted_role = config.muted_role.ok_or(anyhow!(
+                "No muted role configured for guild {}",
+                member.guild_id
+            ))?;
+            info!("adding mute role");
+            member.add_role(ctx, muted_role).await?;
+            Ok(AppliedPunishment::MutedRole(muted_role))
+        }
+        PunishmentMode::Timeout => {
+            let minutes = config.timeout_minutes.clamp(1, MAX_TIMEOUT_MINUTES);
+            let until = Utc::now() + TimeDelta::minutes(minutes.into());
+            info!("timing out user until {until}");
+            member
+                .guild_id
+                .edit_member(
+                    ctx,
+                    member.user.id,
+                    EditMember::new()
+                        .disable_communication_until_datetime(until.into())
+                        .audit_log_reason("Sent a suspicious message"),
+                )
+                .await?;
+            Ok(AppliedPunishment::Timeout)
+        }
+    }
+}
+
+/// Clears whichever punishment was applied when the case was opened.
+async fn lift_punishment(
+    ctx: &serenity::Context,
+    config: &GuildConfig,
+    case: &Case,
+    reason: &str,
+) -> Result<(), Error> {
+    let punishment = match case.punishment {
+        Some(punishment) => punishment,
+        // older cases always used the muted role
+        None => AppliedPunishment::MutedRole(config.muted_role.ok_or(anyhow!(
+            "No muted role configured for guild {}",
+            case.guild_id
+        ))?),
+    };
+    match punishment {
+        AppliedPunishment::MutedRole(muted_role) => {
+            ctx.http
+                .remove_member_role(case.guild_id, case.offender_id, muted_role, Some(reason))
+                .await?;
+        }
+        AppliedPunishment::Timeout => {
+            case.guild_id
+                .edit_member(
+                    ctx,
+                    case.offender_id,
+                    EditMember::new()
+                        .enable_communication()
+                        .audit_log_reason(reason),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the message holding the raw media url once a case no longer needs it.
+async fn cleanup_evidence(ctx: &serenity::Context, case: &Case) {
+    if let (Some(channel), Some(message)) = (case.mod_channel_id, case.evidence_message_id) {
+        let _ = channel.delete_message(ctx, message).await;
+    }
+}
+
+/// Everything that should happen to an offender once their case is decided.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecisionPlan {
+    pub decision: CaseDecision,
+    pub ban: bool,
+    /// Remove the punishment applied while the case was reviewed.
+    pub lift_punishment: bool,
+    pub allow_list: Option<AllowListEntry>,
+    /// Direct message sent to the offender.
+    pub notice: Option<&'static str>,
+    pub audit_reason: &'static str,
+}
+
+impl DecisionPlan {
+    /// What a moderator clicking `action` on `case` results in.
+    pub fn for_action(
+        action: CaseAction,
+        case: &Case,
+        moderator: UserId,
+        now: DateTime<Utc>,
+    ) -> Self {
+        match action {
+            CaseAction::Ban => Self {
+                decision: CaseDecision::Banned,
+                ban: true,
+                lift_punishment: false,
+                allow_list: None,
+                notice: None,
+                audit_reason: "Sending phishing links",
+            },
+            CaseAction::Unmute => Self {
+                decision: CaseDecision::Unmuted,
+                ban: false,
+                lift_punishment: true,
+                allow_list: None,
+                notice: Some("You have been unmuted! Apologies for any confusion"),
+                audit_reason: "Moderator reviewed case",
+            },
+            CaseAction::TempAllowlist => Self {
+                decision: CaseDecision::Allowlisted,
+                ban: false,
+                lift_punishment: true,
+                allow_list: Some(AllowListEntry {
+                    guild_id: case.guild_id,
+                    user_id: case.offender_id,
+                    expires_at: Some(now + TimeDelta::days(1)),
+                    reason: Some(format!("Allowlisted from case #{}", case.id)),
+                    granted_by: moderator,
+                    created_at: now,
+                }),
+                notice: Some("You have been unmuted! You may try and resend your message now."),
+                audit_reason: "Moderator allowlisted user",
+            },
+        }
+    }
+
+    /// What happens when nobody reviews a case in time.
+    pub fn for_expiry() -> Self {
+        Self {
+            decision: CaseDecision::TimedOut,
+            ban: false,
+            lift_punishment: true,
+            allow_list: None,
+            notice: None,
+            audit_reason: "Case review timed out",
+        }
+    }
+
+    async fn execute(
+        &self,
+        ctx: &serenity::Context,
+        database: &Database,
+        config: &GuildConfig,
+        case: &Case,
+    ) -> Result<(), Error> {
+        let offender = case.offender_id;
+        if self.ban {
+            case.guild_id
+                .ban_with_reason(ctx, offender, 3, self.audit_reason)
+                .await?;
+        }
+        if let Some(entry) = &self.allow_list {
+            database.grant_allow_list(entry)?;
+        }
+        if self.lift_punishment {
+            lift_punishment(ctx, config, case, self.audit_reason).await?;
+        }
+        if let Some(notice) = self.notice {
+            // this can definitely fail, but do our best
+            let _ = offender
+                .direct_message(ctx, CreateMessage::new().content(notice))
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Adds a case's media to its guild's image blocklist, the case itself stays open.
+async fn block_case_image(
+    ctx: &serenity::Context,
+    data: &Data,
+    component: &ComponentInteraction,
+    case_id: CaseId,
+) -> Result<(), Error> {
+    let Some(case) = data.database.case(case_id)? else {
+        error!("Block button pressed for unknown case {case_id}");
+        return Ok(());
+    };
+    if component.guild_id != Some(case.guild_id) {
+        return reject_other_guild(ctx, component, case_id).await;
+    }
+    let content = if case.media_hashes.is_empty() {
+        format!("Case #{case_id} has no media to block")
+    } else {
+        let added = data.database.block_images(
+            case.guild_id,
+            &case.media_hashes,
+            Some(case_id),
+            component.user.id,
+            Utc::now(),
+        )?;
+        info!(
+            "{} blocked {added} image hashes from case {case_id}",
+            component.user
+        );
+        format!(
+            "Blocked the media from case #{case_id}, reposts will be removed on sight. Undo with \
+             /unblock_image {case_id}"
+        )
+    };
+    let msg = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(content)
+            .ephemeral(true),
+    );
+    component.create_response(ctx, msg).await?;
+    Ok(())
+}
+
+/// Answers a button pressed for a case from another guild, which should never happen.
+async fn reject_other_guild(
+    ctx: &serenity::Context,
+    component: &ComponentInteraction,
+    case_id: CaseId,
+) -> Result<(), Error> {
+    error!(
+        "{} pressed a button for case {case_id} from another guild",
+        component.user
+    );
+    let msg = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content("This case belongs to another server")
+            .ephemeral(true),
+    );
+    component.create_response(ctx, msg).await?;
+    Ok(())
+}
+
+/// Handles a click on one of the buttons from [`review_buttons`].
+pub async fn handle_component(
+    ctx: &serenity::Context,
+    data: &Data,
+    component: &ComponentInteraction,
+) -> Result<(), Error> {
+    if let Some(case_id) = parse_block_image_custom_id(&component.data.custom_id) {
+        return block_case_image(ctx, data, component, case_id).await;
+    }
+    let Some((case_id, action)) = CaseAction::parse_custom_id(&component.data.custom_id) else {
+        return Ok(());
+    };
+    let Some(case) = data.database.case(case_id)? else {
+        error!("Button pressed for unknown case {case_id}");
+        return Ok(());
+    };
+    if component.guild_id != Some(case.guild_id) {
+        return reject_other_guild(ctx, component, case_id).await;
+    }
+    let user = &component.user;
+    let plan = DecisionPlan::for_action(action, &case, user.id, Utc::now());
+    if !data
+        .database
+        .resolve_case(case_id, plan.decision, Some(user.id))?
+    {
+        let msg = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content("This case has already been resolved")
+                .ephemeral(true),
+        );
+        component.create_response(ctx, msg).await?;
+        return Ok(());
+    }
+
+    info!(
+        "{} user {} after moderator {} reviewed case {}",
+        plan.decision.as_str(),
+        case.offender_id,
+        user,
+        case_id
+    );
+    let config = data.guild_config(case.guild_id);
+    if let Err(e) = plan.execute(ctx, &data.database, &config, &case).await {
+        // leave the case open so the decision can be retried
+        data.database.reopen_case(case_id)?;
+        return Err(e);
+    }
+
+    let text = format!(
+        "{} {} <@{}>",
+        user,
+        plan.decision.as_str(),
+        case.offender_id
+    );
+    let embed = CreateEmbed::default()
+        .title("Moderation Log")
+        .description(text)
+        .color(Color::DARK_GREEN)
+        .footer(CreateEmbedFooter::new(format!("Case #{}", case_id)));
+    component
+        .create_response(
+            ctx,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .components(vec![])
+                    .content("Problem solved")
+                    .embed(embed),
+            ),
+        )
+        .await?;
+    cleanup_evidence(ctx, &case).await;
+    Ok(())
+}
+
+/// Unmutes the offender of a case nobody reviewed in time.
+async fn expire_case(
+    ctx: &serenity::Context,
+    database: &Database,
+    case_id: CaseId,
+) -> Result<(), Error> {
+    let Some(case) = database.case(case_id)? else {
+        return Ok(());
+    };
+    let plan = DecisionPlan::for_expiry();
+    if !database.resolve_case(case_id, plan.decision, None)? {
+        // a moderator got to it first
+        return Ok(());
+    }
+    info!("Timed out, and unmuting the user");
+    let result = match database.guild_config_or_default(case.guild_id) {
+        Ok(config) => plan.execute(ctx, database, &config, &case).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        // the offender is still muted, keep the case pending so the unmute is retried
+        database.reopen_case(case_id)?;
+        return Err(e);
+    }
+    if let (Some(channel), Some(message)) = (case.mod_channel_id, case.mod_message_id) {
+        if let Ok(message) = channel.message(ctx, message).await {
+            message.reply(ctx, "Timed out, unmuting user?").await?;
+        }
+    }
+    cleanup_evidence(ctx, &case).await;
+    Ok(())
+}
+
+/// How often an unmute that failed is tried before the case is left to moderators.
+const EXPIRY_ATTEMPTS: u32 = 3;
+const EXPIRY_RETRY: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Spawns the auto-unmute timer for a case under review.
+pub fn schedule_expiry(
+    ctx: serenity::Context,
+    database: Arc<Database>,
+    case_id: CaseId,
+    expires_at: DateTime<Utc>,
+) {
+    tokio::spawn(async move {
+        let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(remaining).await;
+        for attempt in 1..=EXPIRY_ATTEMPTS {
+            let Err(e) = expire_case(&ctx, &database, case_id).await else {
+                return;
+            };
+            error!("Failed to expire case {case_id}: {e:?}");
+            let retry = if attempt < EXPIRY_ATTEMPTS {
+                format!(", retrying in {} minutes", EXPIRY_RETRY.as_secs() / 60)
+            } else {
+                ", resolve the case to unmute them".to_string()
+            };
+            let mod_channel = database
+                .case(case_id)
+                .ok()
+                .flatten()
+                .and_then(|case| database.guild_config_or_default(case.guild_id).ok())
+                .and_then(|config| config.mod_channel);
+            if let Some(mod_channel) = mod_channel {
+                let _ = mod_channel
+                    .send_message(
+                        &ctx,
+                        CreateMessage::new().content(format!(
+                            "Failed to unmute user for case #{case_id}{retry}: {e:?}"
+                        )),
+                    )
+                    .await;
+            }
+            if attempt < EXPIRY_ATTEMPTS {
+                tokio::time::sleep(EXPIRY_RETRY).await;
+            }
+        }
+    });
+}
+
+/// Restarts the auto-unmute timers of every case that was still under review when the bot stopped.
+pub fn resume_pending_cases(ctx: &serenity::Context, database: &Arc<Database>) {
+    match database.pending_cases() {
+        Ok(cases) => {
+            info!("Resuming {} pending cases", cases.len());
+            for case in cases {
+                if let Some(expires_at) = case.review_expires_at {
+                    schedule_expiry(ctx.clone(), database.clone(), case.id, expires_at);
+                }
+            }
+        }
+        Err(e) => warn!("Unable to load pending cases: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poise::serenity_prelude::{ChannelId, GuildId};
+
+    use super::*;
+
+    fn pending_case() -> Case {
+        Case {
+            id: 7,
+            guild_id: GuildId::new(1),
+            channel_id: ChannelId::new(2),
+            offender_id: UserId::new(3),
+            content: "free nitro https://discorda.org".to_string(),
+            reason: "Misleading URL".to_string(),
+            confidence: None,
+            media_url: None,
+            evidence: None,
+            media_hashes: vec![],
+            created_at: Utc::now(),
+            decision: None,
+            moderator_id: None,
+            decided_at: None,
+            mod_channel_id: Some(ChannelId::new(4)),
+            mod_message_id: None,
+            evidence_message_id: None,
+            review_expires_at: None,
+            punishment: Some(AppliedPunishment::Timeout),
+        }
+    }
+
+    #[test]
+    fn ban_decision() {
+        let case = pending_case();
+        let plan = DecisionPlan::for_action(CaseAction::Ban, &case, UserId::new(9), Utc::now());
+        assert_eq!(plan.decision, CaseDecision::Banned);
+        assert!(plan.ban);
+        assert!(!plan.lift_punishment);
+        assert_eq!(plan.allow_list, None);
+        assert_eq!(plan.notice, None);
+    }
+
+    #[test]
+    fn unmute_decision() {
+        let case = pending_case();
+        let plan = DecisionPlan::for_action(CaseAction::Unmute, &case, UserId::new(9), Utc::now());
+        assert_eq!(plan.decision, CaseDecision::Unmuted);
+        assert!(!plan.ban);
+        assert!(plan.lift_punishment);
+        assert_eq!(plan.allow_list, None);
+        assert!(plan.notice.is_some());
+    }
+
+    #[test]
+    fn temp_allowlist_grants_the_offender() {
+        let case = pending_case();
+        let moderator = UserId::new(9);
+        let now = Utc::now();
+        let plan = DecisionPlan::for_action(CaseAction::TempAllowlist, &case, moderator, now);
+        assert_eq!(plan.decision, CaseDecision::Allowlisted);
+        assert!(!plan.ban);
+        assert!(plan.lift_punishment);
+        let grant = plan.allow_list.expect("allowlist grant");
+        assert_eq!(grant.guild_id, case.guild_id);
+        assert_eq!(grant.user_id, case.offender_id);
+        assert_eq!(grant.granted_by, moderator);
+        assert_eq!(grant.expires_at, Some(now + TimeDelta::days(1)));
+        assert!(grant.reason.unwrap().contains("#7"));
+    }
+
+    #[test]
+    fn expiry_decision() {
+        let plan = DecisionPlan::for_expiry();
+        assert_eq!(plan.decision, CaseDecision::TimedOut);
+        assert!(!plan.ban);
+        assert!(plan.lift_punishment);
+        assert_eq!(plan.allow_list, None);
+    }
+
+    #[test]
+    fn custom_id_round_trip() {
+        for action in [
+            CaseAction::Unmute,
+            CaseAction::TempAllowlist,
+            CaseAction::Ban,
+        ] {
+            assert_eq!(
+                CaseAction::parse_custom_id(&action.custom_id(42)),
+                Some((42, action))
+            );
+        }
+        assert_eq!(CaseAction::parse_custom_id("ban"), None);
+        assert_eq!(CaseAction::parse_custom_id("case:abc:ban"), None);
+        assert_eq!(CaseAction::parse_custom_id("case:1:kick"), None);
+        assert_eq!(CaseAction::parse_custom_id("case:1:ban:extra"), None);
+        // the block button isn't a decision
+        assert_eq!(
+            parse_block_image_custom_id(&block_image_custom_id(42)),
+            Some(42)
+        );
+        assert_eq!(
+            CaseAction::parse_custom_id(&block_image_custom_id(42)),
+            None
+        );
+        assert_eq!(parse_block_image_custom_id("case:42:ban"), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use anyhow::{anyhow, bail, Context};
+use nsfw::{create_model, Model};
+
+/// The nsfw model the detectors use, replaced in place when it's reloaded.
+pub type SharedModel = Arc<RwLock<Arc<Model>>>;
+
+/// Where the model is loaded from when `NSFW_MODEL_PATH` isn't set.
+pub const DEFAULT_MODEL_PATH: &str = "model.onnx";
+
+const DOWNLOAD_URL: &str = "https://github.com/Fyko/nsfw/releases/download/latest/model.onnx";
+
+/// Where the nsfw model comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModelSource {
+    File(PathBuf),
+    /// Compiled into the binary with the `embedded-model` feature.
+    #[cfg(feature = "embedded-model")]
+    Embedded,
+}
+
+impl ModelSource {
+    /// `NSFW_MODEL_PATH` if it's set, otherwise the embedded model if the binary has one, and
+    /// `model.onnx` in the working directory if it doesn't.
+    pub fn from_env() -> Self {
+        match dotenv::var("NSFW_MODEL_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+        {
+            Some(path) => ModelSource::File(path.into()),
+            #[cfg(feature = "embedded-model")]
+            None => ModelSource::Embedded,
+            #[cfg(not(feature = "embedded-model"))]
+            None => ModelSource::File(DEFAULT_MODEL_PATH.into()),
+        }
+    }
+
+    pub fn load(&self) -> anyhow::Result<Model> {
+        let bytes = match self {
+            ModelSource::File(path) => {
+                if !path.is_file() {
+                    bail!(
+                        "{} doesn't exist, download the model from {DOWNLOAD_URL} or point \
+                         NSFW_MODEL_PATH at it",
+                        path.display()
+                    );
+                }
+                fs::read(path).with_context(|| format!("reading {}", path.display()))?
+            }
+            #[cfg(feature = "embedded-model")]
+            ModelSource::Embedded => {
+                include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/model.onnx")).to_vec()
+            }
+        };
+        create_model(Cursor::new(bytes))
+            .map_err(|e| anyhow!("{self} isn't a valid nsfw model: {e}"))
+    }
+}
+
+impl fmt::Display for ModelSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelSource::File(path) => write!(f, "{}", path.display()),
+            #[cfg(feature = "embedded-model")]
+            ModelSource::Embedded => write!(f, "the embedded model"),
+        }
+    }
+}
+
+/// The model currently in use, callers keep their copy even if it's swapped while they run.
+pub fn current(model: &SharedModel) -> Arc<Model> {
+    model.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Loads the model again from `source` and swaps it in, the old model stays if loading fails.
+pub fn reload(model: &SharedModel, source: &ModelSource) -> anyhow::Result<()> {
+    let loaded = source.load()?;
+    *model.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(loaded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_model_explains_where_to_get_it() {
+        let error = ModelSource::File("does/not/exist.onnx".into())
+            .load()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "does/not/exist.onnx doesn't exist, download the model from {DOWNLOAD_URL} or \
+                 point NSFW_MODEL_PATH at it"
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_model_is_rejected() {
+        let path = std::env::temp_dir().join(format!("model-{}.onnx", std::process::id()));
+        fs::write(&path, b"not a model").unwrap();
+        let error = ModelSource::File(path.clone()).load().unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{} isn't a valid nsfw model", path.display())));
+    }
+}
@@ -0,0 +1,1138 @@
+use std::collections::HashSet;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use chrono::{TimeDelta, Utc};
+use lazy_static::lazy_static;
+use log::debug;
+use poise::serenity_prelude::Message;
+use regex::Regex;
+use unicode_security::skeleton;
+use url::{Host, Url};
+
+use crate::detector::{DetectionContext, Detector, Evidence, Verdict};
+use crate::guild_config::{GuildConfig, SignalWeights};
+use crate::phishing_feeds::KnownDomains;
+use crate::rules::{RuleInput, RuleSet};
+use crate::scoring::{Action, Score};
+use crate::{RejectionReason, SpamReason};
+
+lazy_static! {
+    // stops at whitespace and at the brackets used by markdown and angle bracket links
+    static ref URL_REGEX: Regex = Regex::new(r#"(?i)https?://[^\s<>()\[\]"'`]+"#).unwrap();
+    // invites posted without a scheme, the leading character keeps this from matching inside a url
+    static ref BARE_INVITE_REGEX: Regex =
+        Regex::new(r"(?i)(?:^|[^\w./-])((?:www\.)?(?:discord\.gg|discord(?:app)?\.com/invite)/[\w-]+)").unwrap();
+
+    static ref MASKED_LINK: Regex = Regex::new(r"\[(?<text>[^\]]*)\]\(<?(?<url>[^)>\s]+)>?\)").unwrap();
+}
+
+/// Domains discord actually uses, lookalike checks are skipped for these.
+const DISCORD_DOMAINS: [&str; 7] = [
+    "discord.com",
+    "discord.gg",
+    "discord.gift",
+    "discord.media",
+    "discord.new",
+    "discordapp.com",
+    "discordapp.net",
+];
+
+/// Sites that host pages for other brands on subdomains, like `discord.fandom.com`, or whose
+/// names are a few letters from a brand, like `glitch.com`.
+const KNOWN_GOOD_DOMAINS: [&str; 12] = [
+    "bots.gg",
+    "disboard.org",
+    "discogs.com",
+    "fandom.com",
+    "github.com",
+    "glitch.com",
+    "itch.io",
+    "reddit.com",
+    "top.gg",
+    "twitter.com",
+    "wikipedia.org",
+    "x.com",
+];
+
+/// A link found in a message.
+#[derive(Clone, Debug, PartialEq)]
+struct Link<'a> {
+    url: &'a str,
+    /// Lowercase host without credentials or port.
+    host: String,
+    /// Registrable domain of the host according to the public suffix list, e.g. `evil.co.uk` for
+    /// `discord.com.evil.co.uk`. Same as the host for ip addresses.
+    domain: String,
+}
+
+impl<'a> Link<'a> {
+    /// Parses a url, links without a scheme are treated as https.
+    fn parse(url: &'a str) -> Option<Self> {
+        let parsed = if url.contains("://") {
+            Url::parse(url)
+        } else {
+            Url::parse(&format!("https://{url}"))
+        }
+        .ok()?;
+        let host = parsed.host_str()?.trim_end_matches('.').to_lowercase();
+        if host.is_empty() {
+            return None;
+        }
+        let domain = match parsed.host()? {
+            Host::Domain(_) => psl::domain_str(&host).unwrap_or(host.as_str()).to_string(),
+            Host::Ipv4(_) | Host::Ipv6(_) => host.clone(),
+        };
+        Some(Self { url, host, domain })
+    }
+
+    /// The registrable domain without its public suffix, `discord` for `cdn.discord.co.uk`.
+    fn name(&self) -> &str {
+        psl::suffix_str(&self.domain)
+            .and_then(|suffix| self.domain.strip_suffix(suffix))
+            .map(|name| name.trim_end_matches('.'))
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&self.domain)
+    }
+
+    /// Labels in front of the registrable domain, `cdn` and `eu` for `cdn.eu.discord.com`.
+    fn subdomains(&self) -> impl Iterator<Item = &str> {
+        self.host
+            .strip_suffix(self.domain.as_str())
+            .unwrap_or_default()
+            .split('.')
+            .filter(|label| !label.is_empty())
+    }
+
+    /// [`Link::name`] and the subdomains with punycode decoded, `dіscord` for `xn--dscord-6ve`.
+    fn unicode_labels(&self) -> impl Iterator<Item = String> + '_ {
+        std::iter::once(self.name())
+            .chain(self.subdomains())
+            .map(|label| idna::domain_to_unicode(label).0)
+    }
+
+    /// The first entry of a domain list that covers this link's host.
+    fn matching_entry<'l>(&self, list: &'l [String]) -> Option<&'l str> {
+        list.iter()
+            .map(String::as_str)
+            .find(|entry| domain_pattern_matches(entry, &self.host))
+    }
+
+    fn is_discord(&self) -> bool {
+        DISCORD_DOMAINS.contains(&self.domain.as_str())
+    }
+
+    fn is_invite(&self) -> bool {
+        self.domain == "discord.gg"
+            || (self.is_discord() && self.url.to_lowercase().contains("/invite/"))
+    }
+}
+
+/// Whether a subdomain label is the brand itself or a one letter typo of it, on its own or as a
+/// dash separated part like `discord-nitro`. Being stricter than [`typosquatted_brand`] keeps
+/// words like `discover` from matching.
+fn subdomain_imitates(label: &str, brand: &str) -> bool {
+    label.split('-').any(|part| {
+        part == brand || (brand.chars().count() >= 5 && edit_distance(part, brand) == 1)
+    })
+}
+
+/// Whether `label` imitates `brand` with lookalike characters, using the confusable skeletons
+/// from UTS #39 so `dіscord` with a cyrillic і or `stearn` match.
+fn is_homoglyph(label: &str, brand: &str) -> bool {
+    label != brand && skeleton(label).eq(skeleton(brand))
+}
+
+/// Normalizes a domain list entry such as `Example.com`, `*.example.com` or
+/// `https://example.com/path`.
+pub fn parse_domain_pattern(input: &str) -> Result<String, String> {
+    let input = input.trim().to_lowercase();
+    let (wildcard, domain) = match input.strip_prefix("*.") {
+        Some(domain) => (true, domain),
+        None => (false, input.as_str()),
+    };
+    let domain = Link::parse(domain)
+        .map(|link| link.host)
+        .filter(|host| host.contains('.') && !host.contains('*'))
+        .ok_or_else(|| {
+            format!(
+                "`{input}` is not a domain, use something like `example.com` or `*.example.com`"
+            )
+        })?;
+    Ok(if wildcard {
+        format!("*.{domain}")
+    } else {
+        domain
+    })
+}
+
+/// Whether `host` is covered by a domain list entry, `*.example.com` covers example.com and all
+/// of its subdomains.
+fn domain_pattern_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain
+                || host
+                    .strip_suffix(domain)
+                    .map_or(false, |subdomain| subdomain.ends_with('.'))
+        }
+        None => host == pattern,
+    }
+}
+
+/// Edits between two words, where swapping two neighbouring letters like in `discrod` counts as
+/// one edit (optimal string alignment distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // the two rows before the current one of the usual dynamic programming table
+    let mut before = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+/// The protected brand that `name` is a typo of and how many edits away it is.
+fn typosquatted_brand<'a>(name: &str, config: &'a GuildConfig) -> Option<(&'a str, usize)> {
+    config
+        .protected_brands
+        .iter()
+        .map(|brand| (brand.as_str(), edit_distance(name, brand)))
+        .filter(|(brand, distance)| {
+            *distance > 0 && *distance <= config.typosquat_distance.allowed(brand)
+        })
+        .min_by_key(|(_, distance)| *distance)
+}
+
+/// Every link in the message, including masked links, `<https://...>` links and bare invites.
+fn find_links(msg: &str) -> Vec<Link<'_>> {
+    let urls = URL_REGEX
+        .find_iter(msg)
+        .map(|m| m.as_str().trim_end_matches(['.', ',', '!', '?', ';', ':']));
+    let invites = BARE_INVITE_REGEX
+        .captures_iter(msg)
+        .filter_map(|cap| cap.get(1))
+        .map(|m| m.as_str());
+    urls.chain(invites).filter_map(Link::parse).collect()
+}
+
+/// The phishing rules, each adds its own weight to a message's score.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PhishingRule {
+    BlockedDomain,
+    KnownPhishingDomain,
+    Homoglyph,
+    FakeGift,
+    Lookalike,
+    MaskedLink,
+}
+
+impl PhishingRule {
+    pub fn reason(self) -> SpamReason {
+        match self {
+            PhishingRule::BlockedDomain => SpamReason::BlockedDomain,
+            PhishingRule::KnownPhishingDomain => SpamReason::KnownPhishingDomain,
+            PhishingRule::Homoglyph => SpamReason::HomoglyphImpersonation,
+            PhishingRule::Lookalike => SpamReason::UrlDiscordMispell,
+            PhishingRule::FakeGift | PhishingRule::MaskedLink => SpamReason::Phishing,
+        }
+    }
+
+    pub fn weight(self, weights: &SignalWeights) -> u32 {
+        match self {
+            PhishingRule::BlockedDomain => weights.blocked_domain,
+            PhishingRule::KnownPhishingDomain => weights.known_phishing_domain,
+            PhishingRule::Homoglyph => weights.homoglyph,
+            PhishingRule::FakeGift => weights.fake_gift,
+            PhishingRule::Lookalike => weights.lookalike,
+            PhishingRule::MaskedLink => weights.masked_link,
+        }
+    }
+}
+
+/// A rule that matched and the link that triggered it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhishingMatch {
+    pub rule: PhishingRule,
+    pub url: String,
+    /// What the rule noticed about the link, shown to moderators.
+    pub detail: Option<String>,
+}
+
+impl PhishingMatch {
+    fn new(rule: PhishingRule, url: &str) -> Self {
+        Self {
+            rule,
+            url: url.to_string(),
+            detail: None,
+        }
+    }
+
+    fn with_detail(mut self, detail: String) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub fn reason(&self) -> SpamReason {
+        self.rule.reason()
+    }
+
+    /// One line summary for moderators, the link is in a code block so it can't be clicked.
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} `{}`", self.reason().as_str(), self.url);
+        if let Some(detail) = &self.detail {
+            description = format!("{description}, {detail}");
+        }
+        description
+    }
+}
+
+/// Checks if the link looks like a phishing link with the default config and the built in
+/// rules. returns the strongest reason and what the default thresholds do about it, without the
+/// signals that need a member like the account's age
+pub fn check_is_phishing_link(msg: &str) -> Option<(SpamReason, Action)> {
+    let input = RuleInput {
+        content: msg,
+        ..Default::default()
+    };
+    let config = GuildConfig::default();
+    let evaluation = evaluate(
+        input,
+        &config,
+        &KnownDomains::default(),
+        &RuleSet::builtin(),
+    )?;
+    let forced = evaluation.forced_action.map(|(action, _)| action);
+    let action = config
+        .score_thresholds
+        .action(evaluation.score.total())
+        .max(forced)?;
+    Some((evaluation.reason, action))
+}
+
+/// The most severe link rule that matches the message.
+pub fn find_phishing_link(
+    msg: &str,
+    config: &GuildConfig,
+    known: &KnownDomains,
+) -> Option<PhishingMatch> {
+    scan_links(msg, config, known).matches.into_iter().next()
+}
+
+/// The first rule that matches a single link.
+fn check_link(link: &Link, config: &GuildConfig, known: &KnownDomains) -> Option<PhishingMatch> {
+    if let Some(listed) = known.matching(&link.host) {
+        return Some(
+            PhishingMatch::new(PhishingRule::KnownPhishingDomain, link.url)
+                .with_detail(format!("`{listed}` is in a phishing feed")),
+        );
+    }
+    if link.is_discord() {
+        return None;
+    }
+    for label in link.unicode_labels() {
+        if let Some(brand) = config
+            .protected_brands
+            .iter()
+            .find(|brand| is_homoglyph(&label, brand))
+        {
+            return Some(
+                PhishingMatch::new(PhishingRule::Homoglyph, link.url)
+                    .with_detail(format!("`{label}` imitates {brand}")),
+            );
+        }
+    }
+    // Filters all non discord.gift, .gift TLD's
+    if link.host.ends_with(".gift") {
+        debug!("Failed gift url check {}", link.url);
+        return Some(PhishingMatch::new(PhishingRule::FakeGift, link.url));
+    }
+    // twitter.com isn't a typo of twitch, and discord.fandom.com isn't impersonating discord
+    if KNOWN_GOOD_DOMAINS.contains(&link.domain.as_str()) {
+        return None;
+    }
+    if let Some((brand, distance)) = typosquatted_brand(link.name(), config) {
+        return Some(
+            PhishingMatch::new(PhishingRule::Lookalike, link.url).with_detail(format!(
+                "`{}` looks like {brand} (edit distance {distance})",
+                link.name()
+            )),
+        );
+    }
+    // discord.com.evil.xyz
+    if let Some(brand) = config.protected_brands.iter().find(|brand| {
+        link.subdomains()
+            .any(|label| subdomain_imitates(label, brand))
+    }) {
+        debug!("{brand} in subdomain of {}", link.domain);
+        return Some(
+            PhishingMatch::new(PhishingRule::Lookalike, link.url)
+                .with_detail(format!("{brand} used as a subdomain of {}", link.domain)),
+        );
+    }
+    None
+}
+
+/// What the link rules found in a message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkScan<'a> {
+    /// Most severe matches first.
+    pub matches: Vec<PhishingMatch>,
+    /// The first link that isn't blocked or allowed.
+    pub link: Option<&'a str>,
+    /// The first discord invite that isn't blocked or allowed.
+    pub invite: Option<&'a str>,
+}
+
+/// Runs every link in the message through the link rules. Each link matches at most one of the
+/// per link rules.
+pub fn scan_links<'a>(msg: &'a str, config: &GuildConfig, known: &KnownDomains) -> LinkScan<'a> {
+    let mut matches = vec![];
+    // blocked and allowed links skip every other rule
+    let links = find_links(msg)
+        .into_iter()
+        .filter(|link| {
+            if let Some(entry) = link.matching_entry(&config.blocked_domains) {
+                matches.push(
+                    PhishingMatch::new(PhishingRule::BlockedDomain, link.url)
+                        .with_detail(format!("Matched blocklist entry `{entry}`")),
+                );
+                return false;
+            }
+            if let Some(entry) = link.matching_entry(&config.allowed_domains) {
+                debug!("{} is allowed by {entry}", link.url);
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>();
+    matches.extend(
+        links
+            .iter()
+            .filter_map(|link| check_link(link, config, known)),
+    );
+
+    for cap in MASKED_LINK.captures_iter(msg) {
+        let (Some(text), Some(url)) = (cap.name("text"), cap.name("url")) else {
+            continue;
+        };
+        // only links that pretend to go somewhere else are suspicious
+        let Some(shown) = URL_REGEX.find(text.as_str()) else {
+            continue;
+        };
+        let target = Link::parse(url.as_str());
+        let target_allowed = target.as_ref().map_or(false, |link| {
+            link.matching_entry(&config.allowed_domains).is_some()
+        });
+        let shown_host = Link::parse(shown.as_str()).map(|link| link.host);
+        if !target_allowed && shown_host != target.map(|link| link.host) {
+            debug!(
+                "Failed masked url check {} {}",
+                shown.as_str(),
+                url.as_str()
+            );
+            matches.push(
+                PhishingMatch::new(PhishingRule::MaskedLink, url.as_str())
+                    .with_detail(format!("pretends to go to `{}`", shown.as_str())),
+            );
+        }
+    }
+
+    // the same link can show up more than once, e.g. as a masked link's text and target
+    let mut seen = HashSet::new();
+    matches.retain(|found| seen.insert((found.rule, found.url.clone())));
+    LinkScan {
+        matches,
+        link: links.first().map(|link| link.url),
+        invite: links
+            .iter()
+            .find(|link| link.is_invite())
+            .map(|link| link.url),
+    }
+}
+
+/// Everything the link rules and the rule file flagged in a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub score: Score,
+    /// Reason of the heaviest signal.
+    pub reason: SpamReason,
+    /// The most severe action a matching rule asks for whatever the score, and that rule's name.
+    pub forced_action: Option<(Action, String)>,
+}
+
+/// Scores a message against the link rules and the text rules, `None` if nothing matched. The
+/// link and invite of `input` are filled in from the content.
+pub fn evaluate(
+    mut input: RuleInput,
+    config: &GuildConfig,
+    known: &KnownDomains,
+    rules: &RuleSet,
+) -> Option<Evaluation> {
+    let scan = scan_links(input.content, config, known);
+    input.link = scan.link;
+    input.invite = scan.invite;
+    let rule_matches = rules.matches(&input);
+    let weights = &config.signal_weights;
+    let signals = scan
+        .matches
+        .iter()
+        .map(|found| (found.rule.weight(weights), found.describe(), found.reason()))
+        .chain(rule_matches.iter().map(|found| {
+            debug!("rule {} matched {:?}", found.rule.name, found.matched);
+            (
+                found.rule.weight,
+                found.describe(),
+                found.rule.reason.clone(),
+            )
+        }))
+        .collect::<Vec<_>>();
+    // ties go to the more severe signal, which comes first
+    let reason = signals
+        .iter()
+        .rev()
+        .max_by_key(|(weight, _, _)| *weight)?
+        .2
+        .clone();
+    let mut score = Score::default();
+    for (weight, description, _) in signals {
+        score.add(weight, description);
+    }
+    Some(Evaluation {
+        score,
+        reason,
+        forced_action: rule_matches
+            .iter()
+            .filter_map(|found| Some((found.rule.action?, found.rule.name.clone())))
+            // ties go to the rule that's written first
+            .rev()
+            .max_by_key(|(action, _)| *action),
+    })
+}
+
+/// Whether the message tries to ping everyone, even if the author isn't allowed to.
+fn mentions_everyone(message: &Message) -> bool {
+    message.mention_everyone
+        || message.content.contains("@everyone")
+        || message.content.contains("@here")
+}
+
+/// Flags phishing links and other text based spam.
+pub struct PhishingDetector {
+    pub known_domains: Arc<RwLock<KnownDomains>>,
+    pub rules: Arc<RwLock<RuleSet>>,
+}
+
+#[poise::async_trait]
+impl Detector for PhishingDetector {
+    fn name(&self) -> &'static str {
+        "phishing"
+    }
+
+    async fn detect(&self, context: &DetectionContext<'_>) -> Option<Verdict> {
+        let config = context.config;
+        let message = context.message;
+        let input = RuleInput {
+            content: &message.content,
+            embed_titles: message
+                .embeds
+                .iter()
+                .filter_map(|embed| embed.title.as_deref())
+                .collect(),
+            username: &message.author.name,
+            channel_id: Some(message.channel_id),
+            ..Default::default()
+        };
+        let Evaluation {
+            mut score,
+            reason,
+            forced_action,
+        } = {
+            let known = self
+                .known_domains
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let rules = self.rules.read().unwrap_or_else(PoisonError::into_inner);
+            evaluate(input, config, &known, &rules)?
+        };
+        let weights = &config.signal_weights;
+        // these only count once something already looks suspicious
+        if mentions_everyone(context.message) {
+            score.add(weights.everyone_mention, "Mentions everyone");
+        }
+        let account_age = TimeDelta::seconds(
+            Utc::now().timestamp() - context.member.user.created_at().unix_timestamp(),
+        );
+        if account_age < TimeDelta::days(config.new_account_days.into()) {
+            score.add(
+                weights.new_account,
+                format!("Account is {} days old", account_age.num_days()),
+            );
+        }
+        if context.first_message {
+            score.add(weights.first_message, "First message in this server");
+        }
+        let mut evidence = score.breakdown(&config.score_thresholds);
+        let mut action = config.score_thresholds.action(score.total());
+        if let Some((forced, rule)) = forced_action {
+            if Some(forced) > action {
+                evidence += &format!("\nRule `{rule}` asks for {forced:?}");
+                action = Some(forced);
+            }
+        }
+        debug!(
+            "Message {} scored {} {:?}",
+            context.message.id,
+            score.total(),
+            action
+        );
+        Some(Verdict {
+            reason: RejectionReason::SpamReason(reason),
+            confidence: None,
+            evidence: Some(Evidence::Text(evidence)),
+            media_hashes: vec![],
+            action: action?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guild_config::TyposquatDistance;
+    use std::path::Path;
+
+    #[test]
+    fn phishing_test() {
+        // false because not a valid URL, scammers seem to be sending URLs to make it seem official.
+        assert_eq!(
+            check_is_phishing_link(
+                "https:// asdiofjiouawejf.dgidfsdfoiwejrowet.gift aowiejroaiwerj"
+            ),
+            None
+        );
+        // assert_eq!(
+        //     check_is_phishing_link("https://phishinglink-discord.gift/dfoiwejroiwejr"),
+        //     Some(SpamReason::UrlDiscordMispell)
+        // );
+        assert_eq!(
+            check_is_phishing_link("http://discord.gift/notphishing"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("hey pearl i'd like you to sign my autograph for a gift for a friend on discord.com"),
+            None
+        );
+        // assert_eq!(
+        //     check_is_phishing_link("hey check out my spotify free https://spotify.com"),
+        //     None
+        // );
+        // real example, slightly modified
+        assert_eq!(
+            check_is_phishing_link(
+                "@everyone
+                    :video_game: • Get Discord Nitro for Free from Steam Store
+                    Free 3 months Discord Nitro
+                    :clock630: • The offer is valid until at 6:00PM on November 30, 2021.
+                    Personalize your profile, screen share in HD, upgrade your emojis, and more.
+                    :gem: • Click to get Nitro: https://discorda.org/welcome"
+            ),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+
+        // example taken from real phishing attempt and slightly modified, only the terms count
+        assert_eq!(check_is_phishing_link("@​everyone 🔥Airdrop Discord FREE NITRO from Steam — https://discorcla-app.com/redeem/nitro"), Some((SpamReason::Phishing, Action::LogOnly)));
+
+        // Valid discord url
+        assert_eq!(
+            check_is_phishing_link("hello https://discord.com/test-url-blah i am here"),
+            None
+        );
+
+        assert_eq!(
+            check_is_phishing_link("discord.gg/girls hot girls cool cool cool"),
+            Some((SpamReason::SexRelatedTerms, Action::DeleteAndMute))
+        )
+    }
+
+    #[test]
+    fn test_phishing_link_detection() {
+        // Phishing tests
+        assert_eq!(
+            check_is_phishing_link("[Click here](https://phishing.example.com)"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("[http://phishing.example.com](https://not-the-same.com)"),
+            Some((SpamReason::Phishing, Action::Alert))
+        );
+        assert_eq!(
+            check_is_phishing_link("[http://legit.example.com](http://phishing.example.com)"),
+            Some((SpamReason::Phishing, Action::Alert))
+        );
+        assert_eq!(
+            check_is_phishing_link("[http://evil.com](https://good.com)"),
+            Some((SpamReason::Phishing, Action::Alert))
+        );
+
+        // Discord misspelling tests
+        assert_eq!(
+            check_is_phishing_link("[Discord](https://disc0rd.com)"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        assert_eq!(
+            check_is_phishing_link("[Join us](https://discrod.com/server)"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+
+        // Negative tests (not spam)
+        assert_eq!(
+            check_is_phishing_link("[http://example.com](https://example.com)"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("[My Link](https://www.example.com)"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("[Another Link](https://another.example.com)"),
+            None
+        );
+        assert_eq!(check_is_phishing_link("No link here"), None);
+        assert_eq!(
+            check_is_phishing_link("[Image](![alt text](image.jpg))"),
+            None
+        );
+        assert_eq!(check_is_phishing_link("[relative path](/path)"), None);
+        assert_eq!(
+            check_is_phishing_link(
+                "<img src=\"https://legit.example.com/image.jpg\" alt=\"Alt Text\">"
+            ),
+            None
+        );
+
+        // Complex URL tests (handle these carefully)
+        assert_eq!(
+            check_is_phishing_link("[http://example.com/path1/path2](https://example.com/path3)"),
+            None
+        ); // Different paths, same domain (not always phishing)
+        assert_eq!(
+            check_is_phishing_link("[http://example.com](https://example.com/path?param=value)"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("[http://example.com](https://example.com/#fragment)"),
+            None
+        );
+    }
+
+    #[test]
+    fn every_link_is_checked() {
+        let find = |msg| find_phishing_link(msg, &GuildConfig::default(), &KnownDomains::default());
+        assert_eq!(
+            find("official site https://discord.com/ and https://discorda.org/welcome"),
+            Some(
+                PhishingMatch::new(PhishingRule::Lookalike, "https://discorda.org/welcome")
+                    .with_detail("`discorda` looks like discord (edit distance 1)".to_string())
+            )
+        );
+        assert_eq!(
+            find("see <https://discord.com> or <https://disc0rd.com/gift>."),
+            Some(
+                PhishingMatch::new(PhishingRule::Lookalike, "https://disc0rd.com/gift")
+                    .with_detail("`disc0rd` looks like discord (edit distance 1)".to_string())
+            )
+        );
+        assert_eq!(
+            find("[docs](https://example.com) [http://discord.com](https://steam.example)"),
+            Some(
+                PhishingMatch::new(PhishingRule::MaskedLink, "https://steam.example")
+                    .with_detail("pretends to go to `http://discord.com`".to_string())
+            )
+        );
+        assert_eq!(
+            find("https://example.com then https://nitro-discord.gift/claim"),
+            Some(PhishingMatch::new(
+                PhishingRule::FakeGift,
+                "https://nitro-discord.gift/claim"
+            ))
+        );
+        // invites are left to the rule file
+        assert_eq!(find("hot girls here: discord.gg/abc123"), None);
+        assert_eq!(
+            check_is_phishing_link("hot girls here: discord.gg/abc123"),
+            Some((SpamReason::SexRelatedTerms, Action::DeleteAndMute))
+        );
+        assert_eq!(
+            find("https://discord.com@discrod.com/login"),
+            Some(
+                PhishingMatch::new(
+                    PhishingRule::Lookalike,
+                    "https://discord.com@discrod.com/login"
+                )
+                .with_detail("`discrod` looks like discord (edit distance 1)".to_string())
+            )
+        );
+        assert_eq!(
+            find("https://example.com and https://discord.com/channels"),
+            None
+        );
+    }
+
+    #[test]
+    fn finds_links() {
+        let hosts = |msg| {
+            find_links(msg)
+                .into_iter()
+                .map(|link| link.host)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            hosts("[a](https://One.com/x) <http://two.com:8080>, https://three.com. join discord.gg/x"),
+            vec!["one.com", "two.com", "three.com", "discord.gg"]
+        );
+        assert_eq!(hosts("https://discord.gg/abc"), vec!["discord.gg"]);
+        assert!(hosts("https:// nothing.com here").is_empty());
+    }
+
+    #[test]
+    fn compares_registrable_domains() {
+        // brand in front of someone else's domain
+        assert_eq!(
+            check_is_phishing_link("https://discord.com.evil.xyz/login"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        assert_eq!(
+            check_is_phishing_link("https://discord-nitro.gifts.example.co.uk/claim"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        // typo hidden behind subdomains and multi part suffixes
+        assert_eq!(
+            check_is_phishing_link("https://login.discrod.co.uk/"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        assert_eq!(
+            check_is_phishing_link("https://www.dlscord.com.br/app"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        // dots in the path used to end up in the compared domain
+        assert_eq!(
+            check_is_phishing_link("https://disc0rd.com/invite/v1.2"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+        assert_eq!(
+            check_is_phishing_link("https://claim.discord.gift.example.net/"),
+            Some((SpamReason::UrlDiscordMispell, Action::DeleteAndMute))
+        );
+
+        // discord's own domains and subdomains
+        assert_eq!(
+            check_is_phishing_link("https://cdn.discordapp.com/attachments/1/2/cat.png"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("https://media.discordapp.net/attachments/1/2/cat.gif"),
+            None
+        );
+        assert_eq!(
+            check_is_phishing_link("https://canary.discord.com/channels/1/2"),
+            None
+        );
+        assert_eq!(check_is_phishing_link("https://192.168.0.1/router"), None);
+        assert_eq!(
+            check_is_phishing_link("https://discover.example.com/page"),
+            None
+        );
+        // sites that give brands their own subdomain
+        assert_eq!(check_is_phishing_link("https://discord.bots.gg/bots"), None);
+        assert_eq!(
+            check_is_phishing_link("https://discord.fandom.com/wiki/Nitro"),
+            None
+        );
+        // the brand is only part of a word
+        assert_eq!(
+            check_is_phishing_link("https://mydiscordbot.example.com/"),
+            None
+        );
+        // real sites a few letters away from a brand
+        for site in [
+            "https://twitter.com/discord",
+            "https://x.com/twitch",
+            "https://itch.io/games",
+            "https://glitch.com/edit",
+            "https://www.discogs.com/",
+        ] {
+            assert_eq!(check_is_phishing_link(site), None, "{site}");
+        }
+    }
+
+    #[test]
+    fn edit_distance_counts_swaps_once() {
+        assert_eq!(edit_distance("discord", "discord"), 0);
+        assert_eq!(edit_distance("discrod", "discord"), 1);
+        assert_eq!(edit_distance("discorcla", "discord"), 2);
+        assert_eq!(edit_distance("glitch", "twitch"), 2);
+        assert_eq!(edit_distance("", "ea"), 2);
+    }
+
+    #[test]
+    fn homoglyph_impersonation() {
+        let find = |msg| find_phishing_link(msg, &GuildConfig::default(), &KnownDomains::default());
+        // cyrillic і
+        let found = find("free nitro https://d\u{456}scord.com/gift").unwrap();
+        assert_eq!(found.reason(), SpamReason::HomoglyphImpersonation);
+        assert_eq!(found.url, "https://d\u{456}scord.com/gift");
+        assert_eq!(
+            found.detail.as_deref(),
+            Some("`d\u{456}scord` imitates discord")
+        );
+        // the same domain already encoded as punycode
+        let punycode = Url::parse("https://d\u{456}scord.com").unwrap();
+        assert_eq!(
+            check_is_phishing_link(punycode.as_str()),
+            Some((SpamReason::HomoglyphImpersonation, Action::DeleteAndMute))
+        );
+        // rn looks like m
+        assert_eq!(
+            check_is_phishing_link("https://stearncommunity.stearn.ru/trade"),
+            Some((SpamReason::HomoglyphImpersonation, Action::DeleteAndMute))
+        );
+        // in a subdomain
+        assert_eq!(
+            check_is_phishing_link("https://tw\u{456}tch.example.com/login"),
+            Some((SpamReason::HomoglyphImpersonation, Action::DeleteAndMute))
+        );
+
+        assert_eq!(check_is_phishing_link("https://steam.tv/"), None);
+        assert_eq!(check_is_phishing_link("https://m\u{fc}nchen.de/"), None);
+
+        let config = GuildConfig {
+            protected_brands: vec!["potato".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            find_phishing_link(
+                "https://p\u{43e}tato.gg/download",
+                &config,
+                &KnownDomains::default()
+            )
+            .map(|found| found.reason()),
+            Some(SpamReason::HomoglyphImpersonation)
+        );
+        assert_eq!(
+            find_phishing_link("https://stearn.ru/trade", &config, &KnownDomains::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn typosquats_of_configured_brands() {
+        let config = GuildConfig {
+            protected_brands: vec![
+                "discord".to_string(),
+                "epicgames".to_string(),
+                "riotgames".to_string(),
+            ],
+            ..Default::default()
+        };
+        let find = |msg| find_phishing_link(msg, &config, &KnownDomains::default());
+        assert_eq!(
+            find("https://store.epicgamse.com/free").and_then(|found| found.detail),
+            Some("`epicgamse` looks like epicgames (edit distance 1)".to_string())
+        );
+        assert_eq!(
+            find("https://riotgame.net/login").map(|found| found.reason()),
+            Some(SpamReason::UrlDiscordMispell)
+        );
+        assert_eq!(
+            find("https://riotgames.com.login.ru/").and_then(|found| found.detail),
+            Some("riotgames used as a subdomain of login.ru".to_string())
+        );
+        assert_eq!(find("https://epicgames.com/store"), None);
+        // too far from discord
+        assert_eq!(find("https://discorcla.com"), None);
+        assert_eq!(find("https://epikgamse.com/free"), None);
+
+        let loose = GuildConfig {
+            typosquat_distance: TyposquatDistance {
+                max: 2,
+                chars_per_edit: 0,
+            },
+            ..config.clone()
+        };
+        assert!(find_phishing_link(
+            "https://epikgamse.com/free",
+            &loose,
+            &KnownDomains::default()
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn domain_patterns() {
+        assert_eq!(
+            parse_domain_pattern(" *.Example.COM "),
+            Ok("*.example.com".to_string())
+        );
+        assert_eq!(
+            parse_domain_pattern("https://www.example.com/free-stuff"),
+            Ok("www.example.com".to_string())
+        );
+        assert!(parse_domain_pattern("example").is_err());
+        assert!(parse_domain_pattern("*.*.example.com").is_err());
+        assert!(parse_domain_pattern("not a domain").is_err());
+
+        assert!(domain_pattern_matches("example.com", "example.com"));
+        assert!(!domain_pattern_matches("example.com", "www.example.com"));
+        assert!(domain_pattern_matches("*.example.com", "example.com"));
+        assert!(domain_pattern_matches("*.example.com", "a.b.example.com"));
+        assert!(!domain_pattern_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn domain_lists() {
+        let config = GuildConfig {
+            allowed_domains: vec![
+                "*.epicgames.com".to_string(),
+                "store.steampowered.com".to_string(),
+            ],
+            blocked_domains: vec!["*.scam.example".to_string()],
+            ..Default::default()
+        };
+        let find = |msg| find_phishing_link(msg, &config, &KnownDomains::default());
+        let rules = RuleSet::builtin();
+        let check = |msg| {
+            let input = RuleInput {
+                content: msg,
+                ..Default::default()
+            };
+            evaluate(input, &config, &KnownDomains::default(), &rules)
+        };
+        // free next to a trusted link is fine
+        assert_eq!(
+            check("free game today https://store.epicgames.com/p/free-game"),
+            None
+        );
+        assert_eq!(
+            find("[https://store.steampowered.com](https://store.steampowered.com/app/1)"),
+            None
+        );
+        // but only the allowed links are skipped
+        assert_eq!(
+            check("free https://store.epicgames.com and https://example.com")
+                .map(|evaluation| evaluation.score.signals[0].description.clone()),
+            Some("suspicious_terms `free` in content next to `https://example.com`".to_string())
+        );
+        assert_eq!(
+            check("free https://steampowered.com").map(|evaluation| evaluation.reason),
+            Some(SpamReason::Phishing)
+        );
+        assert_eq!(
+            find("look https://cdn.scam.example/cat.png"),
+            Some(
+                PhishingMatch::new(
+                    PhishingRule::BlockedDomain,
+                    "https://cdn.scam.example/cat.png"
+                )
+                .with_detail("Matched blocklist entry `*.scam.example`".to_string())
+            )
+        );
+        assert_eq!(
+            check_is_phishing_link("look https://cdn.scam.example/cat.png"),
+            None
+        );
+    }
+
+    #[test]
+    fn known_phishing_domains() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/phishing_feeds");
+        let known = KnownDomains::load_dir(&dir).unwrap();
+        let config = GuildConfig {
+            allowed_domains: vec!["*.steam-trade.example".to_string()],
+            ..Default::default()
+        };
+        let find = |msg| find_phishing_link(msg, &config, &known);
+        assert_eq!(
+            find("claim it https://cdn.nitro-airdrop.example/claim"),
+            Some(
+                PhishingMatch::new(
+                    PhishingRule::KnownPhishingDomain,
+                    "https://cdn.nitro-airdrop.example/claim"
+                )
+                .with_detail("`nitro-airdrop.example` is in a phishing feed".to_string())
+            )
+        );
+        // the guild's allow list wins over feeds
+        assert_eq!(find("https://steam-trade.example/offer"), None);
+        assert_eq!(find("https://example.com"), None);
+    }
+
+    #[test]
+    fn scores_every_signal() {
+        let config = GuildConfig::default();
+        let rules = RuleSet::builtin();
+        let check = |msg| {
+            let input = RuleInput {
+                content: msg,
+                ..Default::default()
+            };
+            evaluate(input, &config, &KnownDomains::default(), &rules).unwrap()
+        };
+        let evaluation = check("free nitro https://discrod.com/gift https://discrod.com/gift [https://discord.com](https://example.com)");
+        assert_eq!(evaluation.reason, SpamReason::UrlDiscordMispell);
+        assert_eq!(
+            evaluation
+                .score
+                .signals
+                .iter()
+                .map(|signal| signal.weight)
+                .collect::<Vec<_>>(),
+            [60, 50, 30]
+        );
+
+        // free next to a link used to be an instant mute, now it's only logged
+        let free = check("free pizza at https://example.com");
+        assert_eq!(free.score.total(), 30);
+        assert_eq!(free.forced_action, None);
+        assert_eq!(config.score_thresholds.action(30), Some(Action::LogOnly));
+    }
+
+    #[test]
+    fn rules_can_force_an_action() {
+        let rules = RuleSet::parse(
+            r#"
+            [[rule]]
+            name = "crypto_giveaway"
+            keywords = ["airdrop"]
+            action = "delete_and_mute"
+            "#,
+        )
+        .unwrap();
+        let input = RuleInput {
+            content: "AIRDROP for everyone, dm me",
+            ..Default::default()
+        };
+        let evaluation = evaluate(
+            input,
+            &GuildConfig::default(),
+            &KnownDomains::default(),
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(evaluation.reason, SpamReason::SpamRule);
+        assert_eq!(evaluation.score.total(), 0);
+        assert_eq!(
+            evaluation.forced_action,
+            Some((Action::DeleteAndMute, "crypto_giveaway".to_string()))
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::phishing::parse_domain_pattern;
+
+/// Domains from community maintained phishing lists.
+#[derive(Debug, Default)]
+pub struct KnownDomains {
+    domains: HashSet<String>,
+}
+
+/// How a feed file lists its domains.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FeedFormat {
+    /// One domain per line, `#` starts a comment.
+    Text,
+    /// `0.0.0.0 evil.com` lines like in `/etc/hosts`.
+    Hosts,
+    /// An array of domains, or an object with a `domains` array.
+    Json,
+}
+
+impl FeedFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            None | Some("txt") | Some("list") => Some(FeedFormat::Text),
+            Some("hosts") => Some(FeedFormat::Hosts),
+            Some("json") => Some(FeedFormat::Json),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Normalizes an entry the same way domain list entries are, wildcards cover subdomains anyway.
+fn normalize(entry: &str) -> Option<String> {
+    let domain = parse_domain_pattern(entry).ok()?;
+    Some(domain.trim_start_matches("*.").to_string())
+}
+
+fn parse_text(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .filter_map(|line| line.split('#').next()?.split_whitespace().next())
+        .collect()
+}
+
+fn parse_hosts(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .filter_map(|line| line.split('#').next())
+        .flat_map(|line| {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some(address) if address.parse::<IpAddr>().is_ok() => fields.collect(),
+                // lines without an address aren't hosts entries
+                _ => vec![],
+            }
+        })
+        // hosts files map their own loopback names too, and `0.0.0.0 0.0.0.0` lines aren't domains
+        .filter(|host| {
+            !matches!(
+                *host,
+                "localhost"
+                    | "localhost.localdomain"
+                    | "broadcasthost"
+                    | "local"
+                    | "ip6-localhost"
+                    | "ip6-loopback"
+            ) && host.parse::<IpAddr>().is_err()
+        })
+        .collect()
+}
+
+fn parse_json(contents: &str) -> anyhow::Result<Vec<String>> {
+    let value: serde_json::Value = serde_json::from_str(contents)?;
+    let list = match &value {
+        serde_json::Value::Array(list) => list,
+        serde_json::Value::Object(object) => match object.get("domains") {
+            Some(serde_json::Value::Array(list)) => list,
+            _ => anyhow::bail!("expected a `domains` array"),
+        },
+        _ => anyhow::bail!("expected an array of domains"),
+    };
+    Ok(list
+        .iter()
+        .filter_map(|domain| domain.as_str())
+        .map(|domain| domain.to_string())
+        .collect())
+}
+
+impl KnownDomains {
+    /// Loads every feed file directly inside `dir`, files that can't be read are logged and
+    /// skipped so one broken list doesn't disable the rest.
+    pub fn load_dir(dir: &Path) -> anyhow::Result<Self> {
+        let mut known = Self::default();
+        let mut paths = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let Some(format) = FeedFormat::from_path(&path) else {
+                continue;
+            };
+            match known.load_file(&path, format) {
+                Ok(count) => info!("Loaded {count} phishing domains from {}", path.display()),
+                Err(e) => warn!("Skipping phishing feed {}: {e:#}", path.display()),
+            }
+        }
+        info!("{} known phishing domains", known.domains.len());
+        Ok(known)
+    }
+
+    fn load_file(&mut self, path: &Path, format: FeedFormat) -> anyhow::Result<usize> {
+        let contents = fs::read_to_string(path)?;
+        let entries: Vec<String> = match format {
+            FeedFormat::Text => parse_text(&contents)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            FeedFormat::Hosts => parse_hosts(&contents)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            FeedFormat::Json => parse_json(&contents)?,
+        };
+        let before = self.domains.len();
+        self.domains
+            .extend(entries.iter().filter_map(|entry| normalize(entry)));
+        Ok(self.domains.len() - before)
+    }
+
+    /// The listed domain covering `host`, listing a domain also covers its subdomains.
+    pub fn matching(&self, host: &str) -> Option<&str> {
+        let mut domain = host;
+        loop {
+            if let Some(listed) = self.domains.get(domain) {
+                return Some(listed.as_str());
+            }
+            domain = domain.split_once('.')?.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> KnownDomains {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/phishing_feeds");
+        KnownDomains::load_dir(&dir).unwrap()
+    }
+
+    #[test]
+    fn loads_every_format() {
+        let known = fixtures();
+        let mut domains = known.domains.iter().map(String::as_str).collect::<Vec<_>>();
+        domains.sort();
+        assert_eq!(
+            domains,
+            [
+                "discord-nitro.example",
+                "discordgift.example",
+                "free-skins.example",
+                "gift-drop.example",
+                "login.twitch-prime.example",
+                "nitro-airdrop.example",
+                "steam-trade.example",
+                "steamcornmunity.example",
+                "twitch-prime.example",
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_subdomains() {
+        let known = fixtures();
+        assert_eq!(
+            known.matching("discord-nitro.example"),
+            Some("discord-nitro.example")
+        );
+        assert_eq!(
+            known.matching("cdn.gift-drop.example"),
+            Some("gift-drop.example")
+        );
+        assert_eq!(known.matching("example"), None);
+        assert_eq!(known.matching("nitro.example"), None);
+    }
+
+    #[test]
+    fn hosts_skip_addresses_and_localhost() {
+        let hosts = "0.0.0.0 0.0.0.0\n\
+                     127.0.0.1 localhost\n\
+                     ::1 ip6-localhost ip6-loopback\n\
+                     0.0.0.0 127.0.0.1 evil.example # comment\n\
+                     evil2.example";
+        assert_eq!(parse_hosts(hosts), ["evil.example"]);
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(parse_json(r#"{"urls": []}"#).is_err());
+        assert!(parse_json("not json").is_err());
+        assert_eq!(
+            parse_json(r#"["a.example", 3, "b.example"]"#).unwrap(),
+            ["a.example", "b.example"]
+        );
+    }
+}
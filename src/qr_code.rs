@@ -0,0 +1,109 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use image::{imageops, RgbaImage};
+use rqrr::PreparedImage;
+
+use crate::guild_config::GuildConfig;
+use crate::phishing::scan_links;
+use crate::phishing_feeds::KnownDomains;
+use crate::scoring::{Action, Score};
+use crate::SpamReason;
+
+/// Text of every QR code in the image that could be decoded.
+pub fn decode(image: &RgbaImage) -> Vec<String> {
+    let gray = imageops::grayscale(image);
+    let mut prepared = PreparedImage::prepare_from_greyscale(
+        gray.width() as usize,
+        gray.height() as usize,
+        |x, y| gray.get_pixel(x as u32, y as u32)[0],
+    );
+    prepared
+        .detect_grids()
+        .into_iter()
+        .filter_map(|grid| grid.decode().ok())
+        .map(|(_, text)| text)
+        .collect()
+}
+
+/// Phishing links hidden in QR codes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QrFinding {
+    /// Reason of the heaviest link.
+    pub reason: SpamReason,
+    pub score: Score,
+    pub action: Action,
+}
+
+/// Runs the links in decoded QR codes through the same link rules as links in a message, `None`
+/// if nothing matched or the score doesn't reach any threshold.
+pub fn check(texts: &[String], config: &GuildConfig, known: &KnownDomains) -> Option<QrFinding> {
+    let weights = &config.signal_weights;
+    let mut matches = texts
+        .iter()
+        .flat_map(|text| scan_links(text, config, known).matches)
+        .collect::<Vec<_>>();
+    // the same code can be in an image more than once
+    let mut seen = HashSet::new();
+    matches.retain(|found| seen.insert((found.rule, found.url.clone())));
+    matches.sort_by_key(|found| Reverse(found.rule.weight(weights)));
+    let reason = matches.first()?.reason();
+    let mut score = Score::default();
+    for found in &matches {
+        score.add(
+            found.rule.weight(weights),
+            format!("URL found in QR code: {}", found.describe()),
+        );
+    }
+    let action = config.score_thresholds.action(score.total())?;
+    Some(QrFinding {
+        reason,
+        score,
+        action,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use qrcode::{Color, QrCode};
+
+    /// Renders a QR code with a quiet zone, 4 pixels per module.
+    fn render(text: &str) -> RgbaImage {
+        let code = QrCode::new(text).unwrap();
+        let colors = code.to_colors();
+        let width = code.width() as u32;
+        let size = (width + 8) * 4;
+        RgbaImage::from_fn(size, size, |x, y| {
+            let (x, y) = ((x / 4).wrapping_sub(4), (y / 4).wrapping_sub(4));
+            let dark = x < width && y < width && colors[(y * width + x) as usize] == Color::Dark;
+            if dark {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn decodes_qr_codes() {
+        let url = "https://discorda.org/nitro";
+        assert_eq!(decode(&render(url)), vec![url.to_string()]);
+        assert!(decode(&RgbaImage::new(64, 64)).is_empty());
+    }
+
+    #[test]
+    fn qr_links_go_through_the_link_rules() {
+        let config = GuildConfig::default();
+        let known = KnownDomains::default();
+        let finding = check(&["https://discorda.org/nitro".to_string()], &config, &known).unwrap();
+        assert_eq!(finding.reason, SpamReason::UrlDiscordMispell);
+        assert_eq!(finding.action, Action::DeleteAndMute);
+        assert!(finding.score.signals[0]
+            .description
+            .starts_with("URL found in QR code: "));
+        assert!(check(&["https://discord.com".to_string()], &config, &known).is_none());
+        assert!(check(&["just some text".to_string()], &config, &known).is_none());
+    }
+}
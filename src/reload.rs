@@ -0,0 +1,60 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error};
+use tokio::task::spawn_blocking;
+
+/// Modification times of a file, or of every file directly inside a directory.
+fn modified_times(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    let mut times = match path.read_dir() {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let time = modified(&path);
+                (path, time)
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => vec![(path.to_path_buf(), modified(path))],
+    };
+    times.sort();
+    times
+}
+
+/// Polls `path` every `interval` and calls `reload` after a file is added, removed or modified.
+/// Doesn't call `reload` for the state at startup, load that yourself first. Polling and `reload`
+/// run on the blocking thread pool, they read and parse files.
+pub fn watch(path: PathBuf, interval: Duration, mut reload: impl FnMut() + Send + 'static) {
+    tokio::spawn(async move {
+        let mut last = modified_times(&path);
+        loop {
+            tokio::time::sleep(interval).await;
+            let polled = path.clone();
+            let Ok(current) = spawn_blocking(move || modified_times(&polled)).await else {
+                continue;
+            };
+            if current != last {
+                debug!("{} changed, reloading", path.display());
+                last = current;
+                // the closure is handed back so the next change can reuse it
+                reload = match spawn_blocking(move || {
+                    reload();
+                    reload
+                })
+                .await
+                {
+                    Ok(reload) => reload,
+                    Err(e) => {
+                        error!(
+                            "Stopped watching {} after reloading failed: {e}",
+                            path.display()
+                        );
+                        return;
+                    }
+                };
+            }
+        }
+    });
+}
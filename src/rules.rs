@@ -0,0 +1,339 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use poise::serenity_prelude::ChannelId;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::scoring::Action;
+use crate::SpamReason;
+
+/// Rules used when no rule file is configured.
+const BUILTIN_RULES: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/rules.toml"));
+
+/// Part of a message a rule looks at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Content,
+    EmbedTitle,
+    Username,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Content => "content",
+            Scope::EmbedTitle => "embed title",
+            Scope::Username => "username",
+        }
+    }
+}
+
+/// Something else the message needs before a rule can match.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Requirement {
+    /// Any link that isn't on the guild's allow list.
+    Link,
+    /// A discord invite.
+    Invite,
+}
+
+/// A rule as written in the file, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDefinition {
+    name: String,
+    regex: Option<String>,
+    keywords: Option<Vec<String>>,
+    #[serde(default)]
+    scope: Vec<Scope>,
+    requires: Option<Requirement>,
+    weight: Option<u32>,
+    action: Option<Action>,
+    reason: Option<SpamReason>,
+    #[serde(default)]
+    exempt_channels: Vec<ChannelId>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleDefinition>,
+}
+
+/// A validated text spam rule.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pattern: Regex,
+    scopes: Vec<Scope>,
+    requires: Option<Requirement>,
+    pub weight: u32,
+    /// Applied no matter what the message scores.
+    pub action: Option<Action>,
+    pub reason: SpamReason,
+    exempt_channels: Vec<ChannelId>,
+}
+
+impl Rule {
+    fn from_definition(definition: RuleDefinition) -> anyhow::Result<Self> {
+        let pattern = match (definition.regex, definition.keywords) {
+            (Some(regex), None) => Regex::new(&regex).context("invalid regex")?,
+            (None, Some(keywords)) => {
+                if keywords.is_empty() || keywords.iter().any(|k| k.trim().is_empty()) {
+                    bail!("keywords can't be empty");
+                }
+                let alternatives = keywords
+                    .iter()
+                    .map(|keyword| regex::escape(keyword.trim()))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                RegexBuilder::new(&alternatives)
+                    .case_insensitive(true)
+                    .build()?
+            }
+            (Some(_), Some(_)) => bail!("use either regex or keywords, not both"),
+            (None, None) => bail!("needs a regex or keywords"),
+        };
+        if definition.weight.is_none() && definition.action.is_none() {
+            bail!("needs a weight, an action or both");
+        }
+        let scopes = if definition.scope.is_empty() {
+            vec![Scope::Content]
+        } else {
+            definition.scope
+        };
+        Ok(Self {
+            name: definition.name,
+            pattern,
+            scopes,
+            requires: definition.requires,
+            weight: definition.weight.unwrap_or_default(),
+            action: definition.action,
+            reason: definition.reason.unwrap_or(SpamReason::SpamRule),
+            exempt_channels: definition.exempt_channels,
+        })
+    }
+}
+
+// regexes can't be compared, names are unique within a rule set
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+/// The parts of a message rules can look at.
+#[derive(Clone, Debug, Default)]
+pub struct RuleInput<'a> {
+    pub content: &'a str,
+    pub embed_titles: Vec<&'a str>,
+    pub username: &'a str,
+    pub channel_id: Option<ChannelId>,
+    /// First link in the content that isn't on the guild's allow list.
+    pub link: Option<&'a str>,
+    pub invite: Option<&'a str>,
+}
+
+impl RuleInput<'_> {
+    fn texts(&self, scope: Scope) -> Vec<&str> {
+        match scope {
+            Scope::Content => vec![self.content],
+            Scope::EmbedTitle => self.embed_titles.clone(),
+            Scope::Username => vec![self.username],
+        }
+    }
+}
+
+/// A rule that matched and what it matched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleMatch<'r> {
+    pub rule: &'r Rule,
+    pub scope: Scope,
+    pub matched: String,
+    /// The link or invite the rule required.
+    pub url: Option<String>,
+}
+
+impl RuleMatch<'_> {
+    /// One line summary for moderators, links are in a code block so they can't be clicked.
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "{} `{}` in {}",
+            self.rule.name,
+            self.matched,
+            self.scope.as_str()
+        );
+        if let Some(url) = &self.url {
+            description += &format!(" next to `{url}`");
+        }
+        description
+    }
+}
+
+/// Text spam rules, in the order they were written.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Parses and validates a rule file, errors name the rule that's wrong.
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let file: RuleFile = toml::from_str(contents)?;
+        let mut names = HashSet::new();
+        let mut rules = vec![];
+        for (index, definition) in file.rules.into_iter().enumerate() {
+            let name = definition.name.clone();
+            if name.trim().is_empty() {
+                bail!("rule #{} has no name", index + 1);
+            }
+            if !names.insert(name.clone()) {
+                bail!("there's more than one rule named `{name}`");
+            }
+            let rule = Rule::from_definition(definition)
+                .with_context(|| format!("rule #{} `{name}`", index + 1))?;
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("in {}", path.display()))
+    }
+
+    /// The rules shipped in `rules.toml`.
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_RULES).expect("built in rules to be valid")
+    }
+
+    /// Every rule that matches, a rule matches at most once.
+    pub fn matches<'r>(&'r self, input: &RuleInput) -> Vec<RuleMatch<'r>> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                input
+                    .channel_id
+                    .map_or(true, |channel| !rule.exempt_channels.contains(&channel))
+            })
+            .filter_map(|rule| {
+                let url = match rule.requires {
+                    Some(Requirement::Link) => Some(input.link?),
+                    Some(Requirement::Invite) => Some(input.invite?),
+                    None => None,
+                };
+                rule.scopes.iter().find_map(|&scope| {
+                    let matched = input
+                        .texts(scope)
+                        .into_iter()
+                        .find_map(|text| rule.pattern.find(text))?;
+                    Some(RuleMatch {
+                        rule,
+                        scope,
+                        matched: matched.as_str().to_string(),
+                        url: url.map(str::to_string),
+                    })
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_rules_are_valid() {
+        let rules = RuleSet::builtin();
+        assert_eq!(rules.rules.len(), 2);
+    }
+
+    #[test]
+    fn invalid_rules_say_what_is_wrong() {
+        let error = |contents: &str| format!("{:#}", RuleSet::parse(contents).unwrap_err());
+        assert!(error("[[rule]]\nname = \"a\"\nregex = \"(\"\nweight = 1")
+            .starts_with("rule #1 `a`: invalid regex"));
+        assert_eq!(
+            error("[[rule]]\nname = \"a\"\nkeywords = [\"x\"]"),
+            "rule #1 `a`: needs a weight, an action or both"
+        );
+        assert_eq!(
+            error("[[rule]]\nname = \"a\"\nweight = 1"),
+            "rule #1 `a`: needs a regex or keywords"
+        );
+        assert_eq!(
+            error("[[rule]]\nname = \"a\"\nkeywords = [\"x\"]\nweight = 1\n[[rule]]\nname = \"a\"\nkeywords = [\"y\"]\nweight = 1"),
+            "there's more than one rule named `a`"
+        );
+        // typos in keys are caught instead of silently ignored
+        assert!(
+            error("[[rule]]\nname = \"a\"\nkeyword = [\"x\"]\nweight = 1")
+                .contains("unknown field `keyword`")
+        );
+        assert!(
+            error("[[rule]]\nname = \"a\"\nkeywords = [\"x\"]\naction = \"ban\"")
+                .contains("unknown variant `ban`")
+        );
+    }
+
+    #[test]
+    fn rules_respect_scope_requirements_and_exemptions() {
+        let rules = RuleSet::parse(
+            r#"
+            [[rule]]
+            name = "nitro_username"
+            keywords = ["Nitro"]
+            scope = ["username", "embed_title"]
+            action = "alert"
+
+            [[rule]]
+            name = "airdrop"
+            regex = "(?i)air ?drop"
+            requires = "link"
+            weight = 40
+            reason = "phishing"
+            exempt_channels = [5]
+            "#,
+        )
+        .unwrap();
+        let input = RuleInput {
+            content: "AIRDROP today",
+            embed_titles: vec!["Claim your nitro"],
+            username: "bob",
+            channel_id: Some(ChannelId::new(1)),
+            link: Some("https://example.com"),
+            invite: None,
+        };
+        let matches = rules.matches(&input);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(
+            matches[0].describe(),
+            "nitro_username `nitro` in embed title"
+        );
+        assert_eq!(matches[0].rule.action, Some(Action::Alert));
+        assert_eq!(
+            matches[1].describe(),
+            "airdrop `AIRDROP` in content next to `https://example.com`"
+        );
+        assert_eq!(matches[1].rule.reason, SpamReason::Phishing);
+
+        let without_link = RuleInput {
+            link: None,
+            ..input.clone()
+        };
+        assert_eq!(rules.matches(&without_link).len(), 1);
+        let exempt = RuleInput {
+            channel_id: Some(ChannelId::new(5)),
+            ..input
+        };
+        assert_eq!(rules.matches(&exempt).len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude::{GuildId, UserId};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+use crate::guild_config::ScoreThresholds;
+
+/// What happens to a flagged message, from least to most severe.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Only written to the bot's log.
+    LogOnly,
+    /// Posted to the mod channel, the message stays up and nobody is muted.
+    Alert,
+    /// The message is deleted, the author muted and a case opened.
+    DeleteAndMute,
+}
+
+impl ScoreThresholds {
+    /// The most severe action `score` reaches, if any.
+    pub fn action(&self, score: u32) -> Option<Action> {
+        if score >= self.delete_and_mute {
+            Some(Action::DeleteAndMute)
+        } else if score >= self.alert {
+            Some(Action::Alert)
+        } else if score >= self.log_only {
+            Some(Action::LogOnly)
+        } else {
+            None
+        }
+    }
+}
+
+/// One reason a message looks suspicious.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signal {
+    pub weight: u32,
+    pub description: String,
+}
+
+/// Weighted signals that add up to a message's score.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Score {
+    pub signals: Vec<Signal>,
+}
+
+impl Score {
+    /// Signals with no weight are left out so they don't clutter the breakdown.
+    pub fn add(&mut self, weight: u32, description: impl Into<String>) {
+        if weight > 0 {
+            self.signals.push(Signal {
+                weight,
+                description: description.into(),
+            });
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.signals.iter().map(|signal| signal.weight).sum()
+    }
+
+    /// The score and one line per signal, sized for an embed field.
+    pub fn breakdown(&self, thresholds: &ScoreThresholds) -> String {
+        let mut breakdown = format!(
+            "Score **{}** (alert at {}, delete at {})",
+            self.total(),
+            thresholds.alert,
+            thresholds.delete_and_mute
+        );
+        for signal in &self.signals {
+            let line = format!("\n+{} {}", signal.weight, signal.description);
+            // discord rejects embed fields over 1024 characters
+            if breakdown.len() + line.len() > 1000 {
+                breakdown += "\n...";
+                break;
+            }
+            breakdown += &line;
+        }
+        breakdown
+    }
+}
+
+impl Database {
+    /// Remembers that a member posted in a guild, returns true if they never had before. Members
+    /// who only posted before the bot was added look new once.
+    pub fn record_author(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        now: DateTime<Utc>,
+    ) -> rusqlite::Result<bool> {
+        let inserted = self.connection().execute(
+            "INSERT OR IGNORE INTO message_authors (guild_id, user_id, first_seen) VALUES (?1, ?2, ?3)",
+            params![guild_id.get(), user_id.get(), now],
+        )?;
+        Ok(inserted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_pick_the_most_severe_action() {
+        let thresholds = ScoreThresholds {
+            log_only: 20,
+            alert: 40,
+            delete_and_mute: 60,
+        };
+        assert_eq!(thresholds.action(0), None);
+        assert_eq!(thresholds.action(19), None);
+        assert_eq!(thresholds.action(20), Some(Action::LogOnly));
+        assert_eq!(thresholds.action(59), Some(Action::Alert));
+        assert_eq!(thresholds.action(100), Some(Action::DeleteAndMute));
+    }
+
+    #[test]
+    fn breakdown_lists_every_signal() {
+        let mut score = Score::default();
+        score.add(30, "Suspicious terms");
+        score.add(0, "Ignored");
+        score.add(25, "Mentions everyone");
+        assert_eq!(score.total(), 55);
+        assert_eq!(
+            score.breakdown(&ScoreThresholds::default()),
+            "Score **55** (alert at 40, delete at 60)\n+30 Suspicious terms\n+25 Mentions everyone"
+        );
+    }
+
+    #[test]
+    fn first_message_is_only_recorded_once() {
+        let database = Database::open_in_memory().unwrap();
+        let now = Utc::now();
+        let (guild, user) = (GuildId::new(1), UserId::new(2));
+        assert!(database.record_author(guild, user, now).unwrap());
+        assert!(!database.record_author(guild, user, now).unwrap());
+        assert!(database.record_author(GuildId::new(3), user, now).unwrap());
+    }
+}